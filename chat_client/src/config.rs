@@ -0,0 +1,93 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use clap::{App, Arg};
+use serde::Deserialize;
+
+/// The client configuration.
+/// Values are taken from the command line, then from the configuration file (if one was given), then from the defaults
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The address of the chat server, including the port
+    pub server : String,
+    /// The room to join, asked on the console if missing
+    pub room : Option<String>,
+    /// The nickname to use in the room, asked on the console if missing
+    pub nickname : Option<String>,
+    /// Ask the server to relay our own messages back to us, confirming they were delivered to the room
+    pub echo : bool
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            server : "127.0.0.1:7878".to_string(),
+            room : None,
+            nickname : None,
+            echo : false
+        }
+    }
+}
+
+impl Config {
+    /// Build the configuration from the command line arguments, exits the process on invalid arguments
+    pub fn from_args() -> io::Result<Config> {
+        let matches = App::new("chat_client")
+            .about("A console client for the chat server")
+            .arg(Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("TOML configuration file, command line arguments take precedence over it"))
+            .arg(Arg::with_name("server")
+                .short("s")
+                .long("server")
+                .value_name("ADDRESS:PORT")
+                .help("Address of the chat server"))
+            .arg(Arg::with_name("room")
+                .short("r")
+                .long("room")
+                .value_name("ROOM")
+                .help("Room to join"))
+            .arg(Arg::with_name("nickname")
+                .short("n")
+                .long("nickname")
+                .value_name("NICKNAME")
+                .help("Nickname to use in the room"))
+            .arg(Arg::with_name("echo")
+                .short("e")
+                .long("echo")
+                .help("Show our own messages once the server relayed them to the room"))
+            .get_matches();
+
+        let mut config = match matches.value_of("config") {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default()
+        };
+
+        if let Some(server) = matches.value_of("server") {
+            config.server = server.to_string();
+        }
+
+        if let Some(room) = matches.value_of("room") {
+            config.room = Some(room.to_string());
+        }
+
+        if let Some(nickname) = matches.value_of("nickname") {
+            config.nickname = Some(nickname.to_string());
+        }
+
+        if matches.is_present("echo") {
+            config.echo = true;
+        }
+
+        Ok(config)
+    }
+
+    pub fn from_file<P : AsRef<Path>>(path : P) -> io::Result<Config> {
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))
+    }
+}
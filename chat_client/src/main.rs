@@ -1,15 +1,78 @@
 use std::net::{TcpStream};
 use std::io::prelude::*;
 use std::io;
-use std::mem;
-use std::io::{BufReader, BufWriter};
+use std::io::BufReader;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
 
-use tlv_message::message::Message;
+use tlv_message::codec::Codec;
+use tlv_message::compression::Compression;
+use tlv_message::message::{LengthLimit, Message};
+use tlv_message::stream::{MessageReader, MessageWriter};
+use chat_protocol::protocol::{self, Capabilities, ChatMessage, PROTOCOL_VERSION};
+
+mod config;
+
+use crate::config::Config;
+
+fn read_console_line(prompt : &str) -> io::Result<String> {
+    println!("{}", prompt);
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+// The capabilities the client knows how to handle, some of them are only asked for when configured
+const CLIENT_CAPABILITIES : Capabilities = Capabilities::from_bits(Capabilities::COMPRESSION.bits() | Capabilities::CHECKSUM.bits());
+
+fn send<W : Write>(writer : &mut MessageWriter<W>, message : ChatMessage) -> io::Result<()> {
+    send_with_codec(writer, message, Codec::default())
+}
+
+fn send_with_codec<W : Write>(writer : &mut MessageWriter<W>, message : ChatMessage, codec : Codec) -> io::Result<()> {
+    write(writer, &message.encode_with_codec(codec)?)
+}
+
+fn write<W : Write>(writer : &mut MessageWriter<W>, message : &Message) -> io::Result<()> {
+    writer.write_message(message)?;
+    Ok(writer.flush()?)
+}
+
+/// Agree with the server on the protocol version and capabilities, returns the capabilities we may use
+fn say_hello<R : Read, W : Write>(reader : &mut MessageReader<R>, writer : &mut MessageWriter<W>, wanted : Capabilities)
+    -> io::Result<Capabilities> {
+    send(writer, ChatMessage::Hello { version : PROTOCOL_VERSION, capabilities : wanted })?;
+
+    match ChatMessage::decode(&reader.read_message()?) {
+        Ok(ChatMessage::Welcome { version, capabilities }) => {
+            println!("Server speaks protocol version {}", version);
+            Ok(capabilities.negotiate(wanted))
+        },
+        Ok(ChatMessage::Error { reason }) => Err(io::Error::other(reason)),
+        Ok(other) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected reply to hello {:?}", other))),
+        Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// Ask the server to join a room, returns false if the server refused
+fn join_room<R : Read, W : Write>(reader : &mut MessageReader<R>, writer : &mut MessageWriter<W>, room : String, nickname : String)
+    -> io::Result<bool> {
+    send(writer, ChatMessage::Join { room, nickname })?;
+
+    match ChatMessage::decode(&reader.read_message()?) {
+        Ok(ChatMessage::JoinAccepted) => Ok(true),
+        Ok(ChatMessage::JoinRejected { reason }) => {
+            println!("Server refused to join the room: {}", reason);
+            Ok(false)
+        },
+        Ok(other) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected reply to join request {:?}", other))),
+        Err(err) => Err(io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
 
 fn main() -> io::Result<()> {
+    let config = Config::from_args()?;
     let reading = Arc::new(AtomicBool::new(true));
     let r = reading.clone();
     let r2 = reading.clone();
@@ -19,50 +82,93 @@ fn main() -> io::Result<()> {
         r.store(false, Ordering::SeqCst);
     }).expect("Error setting Ctrl-C handler");
 
-    let rstream = TcpStream::connect("127.0.0.1:80")?;
+    // The server gives us a few seconds for the whole handshake, so ask for everything it needs before connecting
+    let room = match config.room {
+        Some(room) => room,
+        None => read_console_line("Room name:")?
+    };
+    let nickname = match config.nickname {
+        Some(nickname) => nickname,
+        None => read_console_line("Nickname:")?
+    };
+
+    let rstream = TcpStream::connect(&config.server)?;
     println!("Connected to chat server");
     let wstream = rstream.try_clone()?;
-    let mut reader = BufReader::new(rstream);
-    let mut writer = BufWriter::new(wstream);
+    let mut reader = MessageReader::new(BufReader::new(rstream));
+    let mut writer = MessageWriter::new(wstream);
+
+    let mut wanted = CLIENT_CAPABILITIES;
+    if config.echo {
+        wanted = wanted | Capabilities::ECHO;
+    }
+    let capabilities = say_hello(&mut reader, &mut writer, wanted)?;
+    println!("Negotiated capabilities {:?}", capabilities);
+    // Pasted logs and the like are compressed, short lines aren't worth it
+    let compression = if capabilities.contains(Capabilities::COMPRESSION) { Some(Compression::default()) } else { None };
+
+    if !join_room(&mut reader, &mut writer, room.clone(), nickname)? {
+        return Ok(());
+    }
+    println!("Joined room {}", room);
+
+    // From now on messages are framed according to the negotiated capabilities
+    let codec = protocol::room_codec(capabilities);
+    let reader = MessageReader::with_codec(reader.into_inner(), codec, LengthLimit::default());
 
     let mut buffer = String::new();
 
     println!("Creating new reader from connection");
 
     let handler = thread::spawn(move || {
-        let mut line = String::new();
-        while r2.load(Ordering::SeqCst) {
-            println!("Reading from connection");
-            let message = Message::from_reader(&mut reader).expect("failed to read from connection");
-            let result = message.data().read_to_string(&mut line).expect("failed to read data from message");
-            println!("Room: {}", line);
-            line.clear();
+        // The server closes the connection once we leave the room, which ends the iteration
+        let limit = LengthLimit::default();
+        for message in reader {
+            let message = match message.and_then(|message| message.decompress(&limit)) {
+                Ok(message) => message,
+                Err(err) => {
+                    println!("Connection to the server failed: {}", err);
+                    break;
+                }
+            };
+
+            match ChatMessage::decode(&message) {
+                Ok(ChatMessage::RelayedText { sender, text, .. }) => println!("{}: {}", sender, text),
+                Ok(ChatMessage::ChatText { text }) => println!("Room: {}", text),
+                Ok(ChatMessage::SystemNotice { text }) => println!("Notice: {}", text),
+                Ok(ChatMessage::Error { reason }) => println!("Error: {}", reason),
+                Ok(other) => println!("Unexpected message from server: {:?}", other),
+                Err(err) => println!("Failed to decode message from server: {}", err)
+            }
+
+            if !r2.load(Ordering::SeqCst) {
+                break;
+            }
         }
     });
 
-    let mut first = true;
     println!("Start client loop");
     while reading.load(Ordering::SeqCst) {
         println!("Reading from console");
-        io::stdin().read_line(&mut buffer)?;
-        if buffer.as_str().eq("stop\n") {
+        // Reaching the end of the input is the same as asking to stop
+        if io::stdin().read_line(&mut buffer)? == 0 || buffer.as_str().eq("stop\n") {
             println!("Stopping");
             reading.store(false, Ordering::SeqCst);
         } else {
-            if !first {
-                println!("Writing to chat {} bytes: {}", buffer.len(), buffer);
-                let message = Message::new(1, buffer.len() as u32, buffer.clone().into_bytes());
-                message.into_writer(writer.by_ref())?;
-            } else {
-                first = false;
-                writer.write(buffer.as_bytes())?;
-            }
-            writer.flush()?;
+            let text = buffer.trim_end().to_string();
+            println!("Writing to chat {} bytes: {}", text.len(), text);
+            let message = ChatMessage::ChatText { text }.encode_with_codec(codec)?;
+            let compressed = match &compression {
+                Some(compression) => message.compressed(compression)?,
+                None => None
+            };
+            write(&mut writer, compressed.as_ref().unwrap_or(&message))?;
         }
 
         buffer.clear();
     }
-    handler.join().expect_err("Error joining thread ");
+    send_with_codec(&mut writer, ChatMessage::Leave, codec)?;
+    handler.join().expect("Error joining thread ");
     println!("done");
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,409 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::ops::{BitAnd, BitOr};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+
+use tlv_message::codec::Codec;
+use tlv_message::message::Message;
+
+/// The revision of the protocol spoken by this crate
+pub const PROTOCOL_VERSION : u16 = 3;
+/// The oldest revision this crate can still talk with
+pub const MIN_PROTOCOL_VERSION : u16 = 2;
+
+/// The longest nickname accepted in a room, in bytes. Nicknames are copied into every message relayed for their owner
+pub const MAX_NICKNAME_LENGTH : usize = 32;
+
+/// The bytes a RelayedText payload adds to the text it relays, besides the sender's nickname: the id, the timestamp and the
+/// sender's length
+pub const RELAYED_TEXT_OVERHEAD : usize = 8 + 8 + 4;
+
+/// The values carried in the TLV type field for each of the chat protocol messages
+pub mod message_type {
+    pub const CHAT_TEXT : u16 = 1;
+    pub const JOIN : u16 = 2;
+    pub const LEAVE : u16 = 3;
+    pub const SYSTEM_NOTICE : u16 = 4;
+    pub const ERROR : u16 = 5;
+    pub const PING : u16 = 6;
+    pub const PONG : u16 = 7;
+    pub const JOIN_ACCEPTED : u16 = 8;
+    pub const JOIN_REJECTED : u16 = 9;
+    pub const HELLO : u16 = 10;
+    pub const WELCOME : u16 = 11;
+    pub const RELAYED_TEXT : u16 = 12;
+}
+
+/// Optional protocol features.
+/// Each peer advertises the features it supports, and the connection uses only the ones both sides support
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE : Capabilities = Capabilities(0);
+    pub const COMPRESSION : Capabilities = Capabilities(1);
+    pub const ACKS : Capabilities = Capabilities(1 << 1);
+    pub const HISTORY_REPLAY : Capabilities = Capabilities(1 << 2);
+    /// The client wants its own chat messages relayed back to it, as a delivery confirmation
+    pub const ECHO : Capabilities = Capabilities(1 << 3);
+    /// Once the client joined a room, every message in both directions ends with a CRC32 of its content
+    pub const CHECKSUM : Capabilities = Capabilities(1 << 4);
+
+    /// Unknown bits are kept, so a newer peer's capabilities survive until they are negotiated away
+    pub const fn from_bits(bits : u32) -> Capabilities {
+        Capabilities(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other : Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The capabilities supported by both sides
+    pub fn negotiate(self, other : Capabilities) -> Capabilities {
+        self & other
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other : Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, other : Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// The codec used once a client joined a room, the handshake always uses the default codec
+pub fn room_codec(capabilities : Capabilities) -> Codec {
+    Codec::default().with_checksum(capabilities.contains(Capabilities::CHECKSUM))
+}
+
+/// All the messages the chat client and the chat server exchange
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChatMessage {
+    /// The first message a client sends, advertising its protocol version and capabilities
+    Hello { version : u16, capabilities : Capabilities },
+    /// The server reply to Hello, with the version and capabilities the connection will use
+    Welcome { version : u16, capabilities : Capabilities },
+    /// Ask to join a chat room, sent once the server welcomed the client
+    Join { room : String, nickname : String },
+    /// The server reply to a successful join
+    JoinAccepted,
+    /// The server refused the join, the connection is closed right after it
+    JoinRejected { reason : String },
+    /// Leave the current chat room
+    Leave,
+    /// A line of text sent to the room
+    ChatText { text : String },
+    /// A line of text relayed by the server to the room, stamped with its sender.
+    /// The timestamp is in milliseconds since the unix epoch. Added in protocol version 3
+    RelayedText { id : u64, timestamp : u64, sender : String, text : String },
+    /// A notice generated by the server (e.g. someone left the room)
+    SystemNotice { text : String },
+    /// The peer did something wrong, the reason is meant to be displayed to the user
+    Error { reason : String },
+    Ping,
+    Pong
+}
+
+/// Failures while turning a Message into a ChatMessage
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The type field doesn't match any known message
+    UnknownType(u32),
+    /// The payload ended before all the fields were read
+    Truncated,
+    /// The payload has more bytes than the message fields
+    TrailingBytes(usize),
+    /// A text field isn't valid UTF-8
+    InvalidText
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnknownType(message_type) => write!(f, "unknown message type {}", message_type),
+            DecodeError::Truncated => write!(f, "message payload is truncated"),
+            DecodeError::TrailingBytes(bytes) => write!(f, "message payload has {} unexpected trailing bytes", bytes),
+            DecodeError::InvalidText => write!(f, "message text is not valid UTF-8")
+        }
+    }
+}
+
+impl std::error::Error for DecodeError { }
+
+impl ChatMessage {
+    pub fn message_type(&self) -> u16 {
+        match self {
+            ChatMessage::Hello { .. } => message_type::HELLO,
+            ChatMessage::Welcome { .. } => message_type::WELCOME,
+            ChatMessage::Join { .. } => message_type::JOIN,
+            ChatMessage::JoinAccepted => message_type::JOIN_ACCEPTED,
+            ChatMessage::JoinRejected { .. } => message_type::JOIN_REJECTED,
+            ChatMessage::Leave => message_type::LEAVE,
+            ChatMessage::ChatText { .. } => message_type::CHAT_TEXT,
+            ChatMessage::RelayedText { .. } => message_type::RELAYED_TEXT,
+            ChatMessage::SystemNotice { .. } => message_type::SYSTEM_NOTICE,
+            ChatMessage::Error { .. } => message_type::ERROR,
+            ChatMessage::Ping => message_type::PING,
+            ChatMessage::Pong => message_type::PONG
+        }
+    }
+
+    /// Encode the message into a TLV message, ready to be written to a stream
+    pub fn encode(&self) -> Message {
+        let data = self.payload();
+        Message::new(self.message_type(), data.len() as u32, data)
+    }
+
+    /// Encode the message using the given codec, fails if the message doesn't fit in the codec's header
+    pub fn encode_with_codec(&self, codec : Codec) -> tlv_message::error::Result<Message> {
+        let data = self.payload();
+        Message::with_codec(codec, u32::from(self.message_type()), data.len() as u32, data)
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut payload = PayloadWriter::new();
+
+        match self {
+            ChatMessage::Hello { version, capabilities } | ChatMessage::Welcome { version, capabilities } => {
+                payload.put_u16(*version);
+                payload.put_u32(capabilities.bits());
+            },
+            ChatMessage::Join { room, nickname } => {
+                payload.put_str(room);
+                payload.put_str(nickname);
+            },
+            ChatMessage::JoinRejected { reason } => payload.put_str(reason),
+            ChatMessage::ChatText { text } => payload.put_str(text),
+            ChatMessage::RelayedText { id, timestamp, sender, text } => {
+                payload.put_u64(*id);
+                payload.put_u64(*timestamp);
+                payload.put_str(sender);
+                payload.put_str(text);
+            },
+            ChatMessage::SystemNotice { text } => payload.put_str(text),
+            ChatMessage::Error { reason } => payload.put_str(reason),
+            ChatMessage::JoinAccepted | ChatMessage::Leave | ChatMessage::Ping | ChatMessage::Pong => {}
+        }
+
+        payload.into_inner()
+    }
+
+    /// Decode a TLV message, fails if the type is unknown or the payload doesn't match the type's layout
+    pub fn decode(message : &Message) -> Result<ChatMessage, DecodeError> {
+        ChatMessage::decode_payload(message.message_type(), message.data())
+    }
+
+    /// Decode a payload which isn't held in a message, e.g. a frame still in a read buffer
+    pub fn decode_payload(message_type : u32, payload : &[u8]) -> Result<ChatMessage, DecodeError> {
+        let mut payload = PayloadReader::new(payload);
+
+        // All the chat messages types fit in the 2 bytes the chat protocol uses
+        let message_type = u16::try_from(message_type).map_err(|_| DecodeError::UnknownType(message_type))?;
+
+        let chat_message = match message_type {
+            message_type::HELLO => ChatMessage::Hello {
+                version : payload.get_u16()?,
+                capabilities : Capabilities::from_bits(payload.get_u32()?)
+            },
+            message_type::WELCOME => ChatMessage::Welcome {
+                version : payload.get_u16()?,
+                capabilities : Capabilities::from_bits(payload.get_u32()?)
+            },
+            message_type::JOIN => ChatMessage::Join {
+                room : payload.get_str()?,
+                nickname : payload.get_str()?
+            },
+            message_type::JOIN_ACCEPTED => ChatMessage::JoinAccepted,
+            message_type::JOIN_REJECTED => ChatMessage::JoinRejected { reason : payload.get_str()? },
+            message_type::LEAVE => ChatMessage::Leave,
+            message_type::CHAT_TEXT => ChatMessage::ChatText { text : payload.get_str()? },
+            message_type::RELAYED_TEXT => ChatMessage::RelayedText {
+                id : payload.get_u64()?,
+                timestamp : payload.get_u64()?,
+                sender : payload.get_str()?,
+                text : payload.get_str()?
+            },
+            message_type::SYSTEM_NOTICE => ChatMessage::SystemNotice { text : payload.get_str()? },
+            message_type::ERROR => ChatMessage::Error { reason : payload.get_str()? },
+            message_type::PING => ChatMessage::Ping,
+            message_type::PONG => ChatMessage::Pong,
+            unknown => return Err(DecodeError::UnknownType(u32::from(unknown)))
+        };
+
+        payload.finish()?;
+        Ok(chat_message)
+    }
+
+    /// Translate the message to the closest message a peer speaking an older protocol version understands
+    pub fn for_version(&self, version : u16) -> ChatMessage {
+        match self {
+            ChatMessage::RelayedText { sender, text, .. } if version < 3 => ChatMessage::ChatText {
+                text : format!("{}: {}", sender, text)
+            },
+            other => other.clone()
+        }
+    }
+}
+
+impl From<&ChatMessage> for Message {
+    fn from(chat_message : &ChatMessage) -> Message {
+        chat_message.encode()
+    }
+}
+
+// Fields are written one after the other, strings are prefixed with their length in bytes
+struct PayloadWriter {
+    data : Vec<u8>
+}
+
+impl PayloadWriter {
+    fn new() -> PayloadWriter {
+        PayloadWriter { data : Vec::new() }
+    }
+
+    fn put_str(&mut self, value : &str) {
+        // Writing into a vector can't fail
+        self.data.write_u32::<NetworkEndian>(value.len() as u32).unwrap();
+        self.data.extend_from_slice(value.as_bytes());
+    }
+
+    fn put_u16(&mut self, value : u16) {
+        self.data.write_u16::<NetworkEndian>(value).unwrap();
+    }
+
+    fn put_u32(&mut self, value : u32) {
+        self.data.write_u32::<NetworkEndian>(value).unwrap();
+    }
+
+    fn put_u64(&mut self, value : u64) {
+        self.data.write_u64::<NetworkEndian>(value).unwrap();
+    }
+
+    fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+struct PayloadReader<'a> {
+    data : &'a [u8]
+}
+
+impl<'a> PayloadReader<'a> {
+    fn new(data : &'a [u8]) -> PayloadReader<'a> {
+        PayloadReader { data }
+    }
+
+    fn get_str(&mut self) -> Result<String, DecodeError> {
+        let length = self.data.read_u32::<NetworkEndian>().map_err(truncated)? as usize;
+        if self.data.len() < length {
+            return Err(DecodeError::Truncated);
+        }
+
+        let (text, rest) = self.data.split_at(length);
+        self.data = rest;
+        String::from_utf8(text.to_vec()).map_err(|_| DecodeError::InvalidText)
+    }
+
+    fn get_u16(&mut self) -> Result<u16, DecodeError> {
+        self.data.read_u16::<NetworkEndian>().map_err(truncated)
+    }
+
+    fn get_u32(&mut self) -> Result<u32, DecodeError> {
+        self.data.read_u32::<NetworkEndian>().map_err(truncated)
+    }
+
+    fn get_u64(&mut self) -> Result<u64, DecodeError> {
+        self.data.read_u64::<NetworkEndian>().map_err(truncated)
+    }
+
+    fn finish(self) -> Result<(), DecodeError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes(self.data.len()))
+        }
+    }
+}
+
+fn truncated(_ : io::Error) -> DecodeError {
+    DecodeError::Truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(chat_message : ChatMessage) {
+        let decoded = ChatMessage::decode(&chat_message.encode()).expect("failed to decode message");
+        assert_eq!(decoded, chat_message);
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        round_trip(ChatMessage::Hello { version : PROTOCOL_VERSION, capabilities : Capabilities::COMPRESSION | Capabilities::ACKS });
+        round_trip(ChatMessage::Welcome { version : PROTOCOL_VERSION, capabilities : Capabilities::NONE });
+        round_trip(ChatMessage::Join { room : "lobby".to_string(), nickname : "alice".to_string() });
+        round_trip(ChatMessage::JoinAccepted);
+        round_trip(ChatMessage::JoinRejected { reason : "room is full".to_string() });
+        round_trip(ChatMessage::Leave);
+        round_trip(ChatMessage::ChatText { text : "hello, world".to_string() });
+        round_trip(ChatMessage::RelayedText { id : 7, timestamp : 1_550_000_000_000, sender : "alice".to_string(), text : "hello".to_string() });
+        round_trip(ChatMessage::SystemNotice { text : "".to_string() });
+        round_trip(ChatMessage::Error { reason : "room is full".to_string() });
+        round_trip(ChatMessage::Ping);
+        round_trip(ChatMessage::Pong);
+    }
+
+    #[test]
+    fn capabilities_negotiation() {
+        let client = Capabilities::COMPRESSION | Capabilities::HISTORY_REPLAY | Capabilities::from_bits(1 << 31);
+        let server = Capabilities::COMPRESSION | Capabilities::ACKS;
+        let negotiated = client.negotiate(server);
+
+        assert!(negotiated.contains(Capabilities::COMPRESSION));
+        assert!(!negotiated.contains(Capabilities::ACKS));
+        assert!(!negotiated.contains(Capabilities::HISTORY_REPLAY));
+        assert_eq!(negotiated, Capabilities::COMPRESSION);
+    }
+
+    #[test]
+    fn relayed_text_for_older_versions() {
+        let relayed = ChatMessage::RelayedText { id : 1, timestamp : 0, sender : "alice".to_string(), text : "hello".to_string() };
+        assert_eq!(relayed.for_version(2), ChatMessage::ChatText { text : "alice: hello".to_string() });
+        assert_eq!(relayed.for_version(PROTOCOL_VERSION), relayed);
+    }
+
+    #[test]
+    fn unknown_type_is_rejected() {
+        let message = Message::new(0xFFFF, 0, Vec::new());
+        match ChatMessage::decode(&message) {
+            Err(DecodeError::UnknownType(0xFFFF)) => {},
+            other => panic!("unexpected decode result {:?}", other)
+        }
+    }
+
+    #[test]
+    fn truncated_payload_is_rejected() {
+        let message = ChatMessage::ChatText { text : "hello".to_string() }.encode();
+        let data = message.data()[..6].to_vec();
+        let message = Message::new(message_type::CHAT_TEXT, data.len() as u32, data);
+        match ChatMessage::decode(&message) {
+            Err(DecodeError::Truncated) => {},
+            other => panic!("unexpected decode result {:?}", other)
+        }
+    }
+}
@@ -1,73 +1,663 @@
 use std::{
+    collections::HashMap,
     io::{self},
-    net::{TcpStream},
-    sync::mpsc,
+    sync::{mpsc, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use tlv_message::message::{AsyncReader, AsyncWriter, Message, AsyncReadResult, AsyncWriteResult};
+use log::{debug, error, info, warn};
+use mio::net::TcpStream;
+use mio::{Events, Poll, Registry, Waker};
+
+use tlv_message::codec::Codec;
+use tlv_message::compression::Compression;
+use tlv_message::error::Error;
+use tlv_message::message::{AsyncWriter, Message};
+use chat_protocol::protocol::{Capabilities, ChatMessage};
+use crate::config::Limits;
+use crate::utilities::overflow_counters::OverflowCounters;
 use crate::utilities::work_token::Token;
 use crate::client::ClientStream;
+use crate::handshake::{self, JoinRequest};
+
+// Token used by the room's waker, client streams are assigned with the tokens following it
+const WAKER : mio::Token = mio::Token(0);
+
+// The number of messages read from a client before the rest of the room gets a turn
+const MAX_MESSAGES_PER_READ : usize = 64;
+
+/// Sending half of a chat room.
+/// Wakes the room's event loop whenever a new client is dispatched to it
+pub struct RoomSender {
+    sender : mpsc::Sender<(TcpStream, JoinRequest)>,
+    waker : Arc<Waker>
+}
+
+/// Receiving half of a chat room, owns the poll driving the room's event loop
+pub struct RoomReceiver {
+    receiver : mpsc::Receiver<(TcpStream, JoinRequest)>,
+    poll : Poll,
+    waker : Arc<Waker>
+}
+
+/// Create a new chat room channel, new clients sent over it will immediately wake the room
+pub fn channel() -> io::Result<(RoomSender, RoomReceiver)> {
+    let (sender, receiver) = mpsc::channel();
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+    Ok((RoomSender { sender, waker : waker.clone() }, RoomReceiver { receiver, poll, waker }))
+}
+
+impl RoomSender {
+    /// Hand a client over to the room, the room decides whether to accept it
+    pub fn send(&self, stream : TcpStream, request : JoinRequest) -> io::Result<()> {
+        self.sender.send((stream, request)).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Chat room is closed"))?;
+        self.waker.wake()
+    }
+}
+
+/// A message waiting to be sent to the room
+struct PendingMessage {
+    // The client which sent the message, None for messages generated by the room itself
+    origin : Option<mio::Token>,
+    message : ChatMessage
+}
+
+impl PendingMessage {
+    fn from_room(message : ChatMessage) -> PendingMessage {
+        PendingMessage { origin : None, message }
+    }
+
+    /// Clients only get their own messages back when they asked for it
+    fn should_deliver_to(&self, token : mio::Token, client : &ClientStream) -> bool {
+        self.origin != Some(token) || client.capabilities().contains(Capabilities::ECHO)
+    }
+}
+
+/// How far reading a client's messages got
+enum ReadStatus {
+    /// Everything the client sent was read
+    Drained,
+    /// The client sent more messages than we read at once
+    Unread,
+    /// The client should be removed from the room
+    Disconnected
+}
 
 pub struct ChatRoom {
     // All client currently in the chat room (Each client has a dedicate stream)
-    stream_list : Vec<ClientStream>,
+    stream_list : HashMap<mio::Token, ClientStream>,
     // All the messages that are waiting to be sent to the streams
-    message_queue: Vec<AsyncWriter<Message>>
+    message_queue: Vec<PendingMessage>,
+    // Clients which sent more messages than we read at once, the rest is read after the next broadcast
+    unread_clients : Vec<mio::Token>,
+    // The token that will be assigned to the next client
+    next_token : usize,
+    // The id that will be assigned to the next relayed message
+    next_message_id : u64,
+    // Limits applied to each of the clients
+    limits : Limits,
+    overflow_counters : Arc<OverflowCounters>
 }
 
 impl ChatRoom {
-    pub fn new() -> ChatRoom {
+    pub fn new(limits : Limits, overflow_counters : Arc<OverflowCounters>) -> ChatRoom {
         ChatRoom {
-            stream_list : Vec::new(),
-            message_queue : Vec::new()
+            stream_list : HashMap::new(),
+            message_queue : Vec::new(),
+            unread_clients : Vec::new(),
+            next_token : WAKER.0 + 1,
+            next_message_id : 0,
+            limits,
+            overflow_counters
+        }
+    }
+
+    /// Accept a new client into the room, unless its nickname is already taken
+    pub fn add_client(&mut self, stream : TcpStream, request : JoinRequest, poll : &Poll) -> io::Result<()> {
+        if self.stream_list.values().any(|client| client.nickname() == request.nickname) {
+            info!("Nickname {} is already taken in room {}", request.nickname, request.room);
+            let reason = format!("Nickname {} is already taken in this room", request.nickname);
+            return handshake::reply(&stream, ChatMessage::JoinRejected { reason });
+        }
+
+        let token = mio::Token(self.next_token);
+        self.next_token += 1;
+
+        let mut client = ClientStream::build(stream, token, request.nickname, request.version, request.capabilities,
+                                             self.limits.clone(), self.overflow_counters.clone());
+        client.register(poll.registry())?;
+        // The acceptance is the last handshake message, so it uses the default codec.
+        // It is queued like any other message, ahead of everything the room sends the client
+        if let Err(err) = client.write_messages_to_stream(vec![AsyncWriter::new(ChatMessage::JoinAccepted.encode())], poll.registry()) {
+            let _ = client.deregister(poll.registry());
+            return Err(err);
+        }
+        let notice = ChatMessage::SystemNotice { text : format!("{} joined the room", client.nickname()) };
+        self.message_queue.push(PendingMessage::from_room(notice));
+        self.stream_list.insert(token, client);
+        Ok(())
+    }
+
+    /// Read the messages a client has sent us.
+    /// Readiness is edge triggered, so we must keep reading until the stream has nothing left for us.
+    /// A client sending more than we read at once is read again after the next broadcast, see `read_unread_clients`
+    pub fn look_for_new_messages(&mut self, token : mio::Token, poll : &Poll) {
+        let status = match self.stream_list.get_mut(&token) {
+            Some(stream) => ChatRoom::read_client_messages(token, stream, &mut self.message_queue, &mut self.next_message_id,
+                                                           poll.registry()),
+            None => return
+        };
+
+        match status {
+            ReadStatus::Drained => {},
+            ReadStatus::Unread => {
+                if !self.unread_clients.contains(&token) {
+                    self.unread_clients.push(token);
+                }
+            },
+            ReadStatus::Disconnected => self.remove_client(token, poll)
         }
     }
 
-    pub fn look_for_new_message(&mut self) {
-        let stream_list = &mut self.stream_list;
-        let message_queue = &mut self.message_queue;
+    /// Whether some clients have messages we didn't read yet, no readiness event is coming for them
+    pub fn has_unread_clients(&self) -> bool {
+        !self.unread_clients.is_empty()
+    }
+
+    /// Continue reading from the clients which sent more messages than we read at once
+    pub fn read_unread_clients(&mut self, poll : &Poll) {
+        for token in std::mem::take(&mut self.unread_clients) {
+            self.look_for_new_messages(token, poll);
+        }
+    }
 
-        stream_list.iter_mut().for_each(|stream| {
-            if let Some(message) = stream.read_message() {
-                message_queue.push(AsyncWriter::<Message>::new(message));
+    fn read_client_messages(token : mio::Token, stream : &mut ClientStream, message_queue : &mut Vec<PendingMessage>,
+                            next_message_id : &mut u64, registry : &Registry) -> ReadStatus {
+        for _ in 0..MAX_MESSAGES_PER_READ {
+            let frame = match stream.read_message() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return ReadStatus::Drained,
+                Err(err @ Error::FrameTooLarge { .. }) | Err(err @ Error::ChecksumMismatch { .. }) | Err(err @ Error::ProtocolViolation(_)) => {
+                    // Either the stream is out of sync (the rest of the message is never read, or its length was corrupted),
+                    // or the client doesn't follow the protocol. Let the client know why it is removed
+                    warn!("Disconnecting {}: {}", stream.nickname(), err);
+                    let _ = stream.send(&ChatMessage::Error { reason : err.to_string() }, registry);
+                    return ReadStatus::Disconnected;
+                },
+                Err(err) => {
+                    debug!("Failed to read from client {}", err);
+                    return ReadStatus::Disconnected;
+                }
+            };
+
+            let reply = match ChatMessage::decode_payload(frame.message_type(), frame.payload()) {
+                Ok(ChatMessage::ChatText { text }) => {
+                    // Stamp the message, so the rest of the room knows who sent it and when
+                    let message = ChatMessage::RelayedText {
+                        id : *next_message_id,
+                        timestamp : now_in_millis(),
+                        sender : stream.nickname().to_string(),
+                        text
+                    };
+                    message_queue.push(PendingMessage { origin : Some(token), message });
+                    *next_message_id += 1;
+                    continue;
+                },
+                Ok(ChatMessage::Ping) => ChatMessage::Pong,
+                Ok(ChatMessage::Leave) => return ReadStatus::Disconnected,
+                Ok(other) => ChatMessage::Error {
+                    reason : format!("Unexpected message of type {}", other.message_type())
+                },
+                Err(err) => ChatMessage::Error { reason : err.to_string() }
+            };
+
+            if let Err(err) = stream.send(&reply, registry) {
+                warn!("Failed to write to client {}", err);
+                return ReadStatus::Disconnected;
             }
-        });
+        }
+
+        ReadStatus::Unread
     }
 
-    pub fn broadcast_pending_messages(&mut self) {
-        // Loop over through all streams, clone pending messages to stream, and distribute them
-        for stream in &mut self.stream_list {
-            stream.write_messages_to_stream(self.message_queue.clone());
+    /// Continue writing the messages a client couldn't receive so far, now that its stream is writable
+    pub fn write_pending_messages(&mut self, token : mio::Token, poll : &Poll) {
+        let result = match self.stream_list.get_mut(&token) {
+            Some(stream) => stream.write_pending_messages(poll.registry()),
+            None => return
+        };
+
+        if let Err(err) = result {
+            warn!("Failed to write to client {}", err);
+            self.remove_client(token, poll);
+        }
+    }
+
+    /// Remove a client from the room, and let the rest of the room know about it
+    fn remove_client(&mut self, token : mio::Token, poll : &Poll) {
+        if let Some(mut stream) = self.stream_list.remove(&token) {
+            info!("Removing {} from the room", stream.nickname());
+            if let Err(err) = stream.deregister(poll.registry()) {
+                warn!("Failed to deregister client {}", err);
+            }
+
+            let notice = ChatMessage::SystemNotice { text : format!("{} left the room", stream.nickname()) };
+            self.message_queue.push(PendingMessage::from_room(notice));
+        }
+    }
+
+    pub fn broadcast_pending_messages(&mut self, poll : &Poll) {
+        // Removing a client queues a notice for the rest of the room, so keep going until nothing is left
+        while !self.message_queue.is_empty() {
+            // All messages are about to be in the streams internal queues, so we can clear this queue
+            let messages = std::mem::take(&mut self.message_queue);
+            let mut disconnected = Vec::new();
+            // Messages are encoded (and compressed) once for each protocol version and codec used in the room,
+            // the streams share the encoded frames and only keep track of their own progress
+            let mut encoded_messages = HashMap::<(u16, Codec, bool), Vec<Option<Arc<Message>>>>::new();
+            let compression = self.limits.compression();
+
+            // Loop over through all streams, and distribute the pending messages to them
+            for (token, stream) in self.stream_list.iter_mut() {
+                let (version, codec) = (stream.version(), stream.codec());
+                let plain = encoded_messages.entry((version, codec, false))
+                    .or_insert_with(|| encode_frames(&messages, version, codec))
+                    .clone();
+                let frames = if stream.capabilities().contains(Capabilities::COMPRESSION) {
+                    encoded_messages.entry((version, codec, true)).or_insert_with(|| compress_frames(&plain, &compression))
+                } else {
+                    &plain
+                };
+
+                let writers = messages.iter().zip(frames.iter())
+                    .filter(|(pending, _)| pending.should_deliver_to(*token, stream))
+                    .filter_map(|(_, frame)| frame.clone().map(AsyncWriter::shared))
+                    .collect();
+
+                if let Err(err) = stream.write_messages_to_stream(writers, poll.registry()) {
+                    warn!("Failed to write to client {}", err);
+                    disconnected.push(*token);
+                }
+            }
+
+            for token in disconnected {
+                self.remove_client(token, poll);
+            }
         }
-        // All messages are now in the streams internal queues, so we can clear this queue
-        self.message_queue.clear();
     }
 }
 
-pub fn chat_room_handler(receiver: mpsc::Receiver<TcpStream>, cancellation_token : Token) -> io::Result<()> {
-    println!("Opening new chat room");
+pub fn chat_room_handler(room : RoomReceiver, limits : Limits, overflow_counters : Arc<OverflowCounters>,
+                         cancellation_token : Token) -> io::Result<()> {
+    info!("Opening new chat room");
+
+    let RoomReceiver { receiver, mut poll, waker } = room;
+    // Make sure a cancellation wakes us up, instead of waiting for the next client event
+    cancellation_token.register_waker(waker);
 
-    let mut chat_room = ChatRoom::new();
+    let mut chat_room = ChatRoom::new(limits, overflow_counters);
+    let mut events = Events::with_capacity(128);
 
     loop {
         if cancellation_token.canceled() {
             break;
         }
-        // Look for a new connection to the chat room
-        let _ = receiver.try_recv().map(|stream| {
-            println!("Received a new connection to the room");
-            stream.set_nonblocking(true).expect("Failed to set socket as non blocking");
-            chat_room.stream_list.push(ClientStream::build(stream));
-        });
-        println!("looking for new messages");
-        // Look for a new message from any of the room's clients
-        chat_room.look_for_new_message();
-        println!("broadcasting pending messages");
+
+        // Wait until a client sends us something, a new connection arrives, or we are canceled.
+        // Clients with unread messages won't get another event, so only check for events if some are left
+        let timeout = if chat_room.has_unread_clients() { Some(Duration::ZERO) } else { None };
+        if let Err(err) = poll.poll(&mut events, timeout) {
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+
+        chat_room.read_unread_clients(&poll);
+
+        for event in events.iter() {
+            match event.token() {
+                WAKER => {
+                    // Look for new connections to the chat room
+                    while let Ok((stream, request)) = receiver.try_recv() {
+                        debug!("Received a new connection to the room");
+                        if let Err(err) = chat_room.add_client(stream, request, &poll) {
+                            error!("Failed to add client to the room {}", err);
+                        }
+                    }
+                },
+                token => {
+                    // Look for new messages from the client, a closed or failed stream is noticed when reading from it
+                    if event.is_readable() || event.is_read_closed() || event.is_error() {
+                        chat_room.look_for_new_messages(token, &poll);
+                    }
+
+                    if event.is_writable() {
+                        chat_room.write_pending_messages(token, &poll);
+                    }
+                }
+            }
+        }
+
         // Broadcast received messages to all room's members
-        chat_room.broadcast_pending_messages();
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        chat_room.broadcast_pending_messages(&poll);
     }
 
-    println!("Closing chat room");
+    info!("Closing chat room");
     Ok(())
 }
+
+// The frames match the messages one to one, a message which failed to encode is skipped
+fn encode_frames(messages : &[PendingMessage], version : u16, codec : Codec) -> Vec<Option<Arc<Message>>> {
+    messages.iter().map(|pending| match pending.message.for_version(version).encode_with_codec(codec) {
+        Ok(message) => Some(Arc::new(message)),
+        Err(err) => {
+            warn!("Failed to encode message, skipping it {}", err);
+            None
+        }
+    }).collect()
+}
+
+// Frames which don't benefit from compression are shared with the clients which don't compress
+fn compress_frames(frames : &[Option<Arc<Message>>], compression : &Compression) -> Vec<Option<Arc<Message>>> {
+    frames.iter().map(|frame| frame.as_ref().map(|frame| match frame.compressed(compression) {
+        Ok(Some(compressed)) => Arc::new(compressed),
+        Ok(None) => frame.clone(),
+        Err(err) => {
+            warn!("Failed to compress message, sending it uncompressed {}", err);
+            frame.clone()
+        }
+    })).collect()
+}
+
+fn now_in_millis() -> u64 {
+    // A clock set before the epoch is not worth failing the message for
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_millis() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{self, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+    use tlv_message::message::LengthLimit;
+    use chat_protocol::protocol::{self, PROTOCOL_VERSION};
+    use crate::handshake::{Handshake, Progress};
+    use super::*;
+
+    // A room served from the test thread, clients join it over loopback connections
+    struct TestRoom {
+        room : ChatRoom,
+        poll : Poll,
+        listener : TcpListener
+    }
+
+    impl TestRoom {
+        fn new() -> TestRoom {
+            TestRoom {
+                room : ChatRoom::new(Limits::default(), Arc::new(OverflowCounters::default())),
+                poll : Poll::new().unwrap(),
+                listener : TcpListener::bind("127.0.0.1:0").unwrap()
+            }
+        }
+
+        // The server end of a loopback connection, and the client end
+        fn connect(&self) -> (TcpStream, net::TcpStream) {
+            let client = net::TcpStream::connect(self.listener.local_addr().unwrap()).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let (server, _) = self.listener.accept().unwrap();
+            server.set_nonblocking(true).unwrap();
+            (TcpStream::from_std(server), client)
+        }
+
+        // Returns the client end of the connection
+        fn join(&mut self, nickname : &str, capabilities : Capabilities) -> net::TcpStream {
+            let (server, client) = self.connect();
+            let request = JoinRequest {
+                room : "lobby".to_string(),
+                nickname : nickname.to_string(),
+                version : PROTOCOL_VERSION,
+                capabilities
+            };
+            self.room.add_client(server, request, &self.poll).unwrap();
+            client
+        }
+
+        // Handle everything the clients sent, the same way the room's event loop does, until they are quiet
+        fn serve(&mut self) {
+            let mut events = Events::with_capacity(128);
+            loop {
+                self.room.broadcast_pending_messages(&self.poll);
+                let timeout = if self.room.has_unread_clients() { Duration::ZERO } else { Duration::from_millis(100) };
+                self.poll.poll(&mut events, Some(timeout)).unwrap();
+                if events.is_empty() && !self.room.has_unread_clients() {
+                    return;
+                }
+
+                self.room.read_unread_clients(&self.poll);
+
+                for event in events.iter() {
+                    if event.is_readable() || event.is_read_closed() || event.is_error() {
+                        self.room.look_for_new_messages(event.token(), &self.poll);
+                    }
+
+                    if event.is_writable() {
+                        self.room.write_pending_messages(event.token(), &self.poll);
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(mut client : &net::TcpStream, message : &ChatMessage, codec : Codec) {
+        let mut data = Vec::new();
+        message.encode_with_codec(codec).unwrap().into_writer(&mut data).unwrap();
+        client.write_all(&data).unwrap();
+    }
+
+    fn receive(mut client : &net::TcpStream, codec : Codec) -> ChatMessage {
+        ChatMessage::decode(&Message::from_reader_with_codec(&mut client, codec, &LengthLimit::default()).unwrap()).unwrap()
+    }
+
+    // The acceptance, and the notices for the clients which joined so far
+    fn receive_join(client : &net::TcpStream, codec : Codec, nicknames : &[&str]) {
+        assert_eq!(receive(client, Codec::default()), ChatMessage::JoinAccepted);
+        for nickname in nicknames {
+            assert_eq!(receive(client, codec), notice(&format!("{} joined the room", nickname)));
+        }
+    }
+
+    fn notice(text : &str) -> ChatMessage {
+        ChatMessage::SystemNotice { text : text.to_string() }
+    }
+
+    #[test]
+    fn closed_client_is_removed_and_announced() {
+        let mut room = TestRoom::new();
+        let alice = room.join("alice", Capabilities::NONE);
+        let bob = room.join("bob", Capabilities::NONE);
+        room.serve();
+        receive_join(&alice, Codec::default(), &["alice", "bob"]);
+
+        drop(bob);
+        room.serve();
+        assert_eq!(receive(&alice, Codec::default()), notice("bob left the room"));
+        assert_eq!(room.room.stream_list.len(), 1);
+
+        // The rest of the room is still served
+        send(&alice, &ChatMessage::Ping, Codec::default());
+        room.serve();
+        assert_eq!(receive(&alice, Codec::default()), ChatMessage::Pong);
+    }
+
+    #[test]
+    fn taken_nickname_is_rejected() {
+        let mut room = TestRoom::new();
+        let _alice = room.join("alice", Capabilities::NONE);
+        let impostor = room.join("alice", Capabilities::NONE);
+
+        assert!(matches!(receive(&impostor, Codec::default()), ChatMessage::JoinRejected { .. }));
+        assert_eq!(room.room.stream_list.len(), 1);
+    }
+
+    #[test]
+    fn relayed_text_is_stamped_with_its_sender() {
+        let mut room = TestRoom::new();
+        let alice = room.join("alice", Capabilities::NONE);
+        let bob = room.join("bob", Capabilities::NONE);
+        room.serve();
+        receive_join(&bob, Codec::default(), &["alice", "bob"]);
+
+        let before = now_in_millis();
+        send(&alice, &ChatMessage::ChatText { text : "first".to_string() }, Codec::default());
+        send(&alice, &ChatMessage::ChatText { text : "second".to_string() }, Codec::default());
+        room.serve();
+
+        let mut last_id = None;
+        for expected in &["first", "second"] {
+            match receive(&bob, Codec::default()) {
+                ChatMessage::RelayedText { id, timestamp, sender, text } => {
+                    assert_eq!((sender.as_str(), text.as_str()), ("alice", *expected));
+                    assert!(timestamp >= before && timestamp <= now_in_millis());
+                    assert!(last_id < Some(id));
+                    last_id = Some(id);
+                },
+                other => panic!("Expected a relayed text, got {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn own_messages_are_only_echoed_when_asked_for() {
+        let mut room = TestRoom::new();
+        let alice = room.join("alice", Capabilities::NONE);
+        let bob = room.join("bob", Capabilities::ECHO);
+        room.serve();
+        receive_join(&alice, Codec::default(), &["alice", "bob"]);
+        receive_join(&bob, Codec::default(), &["alice", "bob"]);
+
+        send(&alice, &ChatMessage::ChatText { text : "from alice".to_string() }, Codec::default());
+        room.serve();
+        send(&bob, &ChatMessage::ChatText { text : "from bob".to_string() }, Codec::default());
+        room.serve();
+
+        let relayed_text = |client : &net::TcpStream| match receive(client, Codec::default()) {
+            ChatMessage::RelayedText { sender, text, .. } => (sender, text),
+            other => panic!("Expected a relayed text, got {:?}", other)
+        };
+        // Alice didn't get her own message back, so the first one she got is from bob
+        assert_eq!(relayed_text(&alice), ("bob".to_string(), "from bob".to_string()));
+        assert_eq!(relayed_text(&bob), ("alice".to_string(), "from alice".to_string()));
+        assert_eq!(relayed_text(&bob), ("bob".to_string(), "from bob".to_string()));
+    }
+
+    // The client is told why, and its connection is closed
+    fn assert_disconnected_with_error(room : &TestRoom, client : &net::TcpStream, codec : Codec) {
+        assert!(matches!(receive(client, codec), ChatMessage::Error { .. }));
+        assert!(Message::from_reader_with_codec(&mut &*client, codec, &LengthLimit::default()).is_err());
+        assert!(room.room.stream_list.is_empty());
+    }
+
+    #[test]
+    fn corrupted_message_disconnects_the_client() {
+        let mut room = TestRoom::new();
+        let codec = protocol::room_codec(Capabilities::CHECKSUM);
+        let mut alice = room.join("alice", Capabilities::CHECKSUM);
+        room.serve();
+        receive_join(&alice, codec, &["alice"]);
+
+        let mut data = Vec::new();
+        ChatMessage::ChatText { text : "hello".to_string() }.encode_with_codec(codec).unwrap().into_writer(&mut data).unwrap();
+        // Flip a bit of the text, ahead of the checksum
+        let corrupted = data.len() - codec.trailer_length() - 1;
+        data[corrupted] ^= 1;
+        alice.write_all(&data).unwrap();
+        room.serve();
+
+        assert_disconnected_with_error(&room, &alice, codec);
+    }
+
+    #[test]
+    fn compressed_message_is_rejected_unless_negotiated() {
+        let mut room = TestRoom::new();
+        let mut alice = room.join("alice", Capabilities::NONE);
+        room.serve();
+        receive_join(&alice, Codec::default(), &["alice"]);
+
+        let message = ChatMessage::ChatText { text : "a".repeat(4096) }.encode();
+        let compressed = message.compressed(&Compression::default()).unwrap().unwrap();
+        compressed.write_to(&mut alice).unwrap();
+        room.serve();
+
+        assert_disconnected_with_error(&room, &alice, Codec::default());
+    }
+
+    #[test]
+    fn messages_sent_with_the_join_request_reach_the_room() {
+        let mut room = TestRoom::new();
+        let bob = room.join("bob", Capabilities::NONE);
+        let (server, alice) = room.connect();
+
+        // Everything is sent at once, so the text is already buffered when the room registers the stream
+        let mut data = Vec::new();
+        for message in &[
+            ChatMessage::Hello { version : PROTOCOL_VERSION, capabilities : Capabilities::NONE },
+            ChatMessage::Join { room : "lobby".to_string(), nickname : "alice".to_string() },
+            ChatMessage::ChatText { text : "hello everyone".to_string() }
+        ] {
+            message.encode().into_writer(&mut data).unwrap();
+        }
+        (&alice).write_all(&data).unwrap();
+
+        let mut handshake = Handshake::new(server, &Limits::default());
+        let mut handshake_poll = Poll::new().unwrap();
+        handshake.register(handshake_poll.registry(), mio::Token(0)).unwrap();
+        handshake_poll.poll(&mut Events::with_capacity(1), Some(Duration::from_secs(5))).unwrap();
+        let request = match handshake.advance().unwrap() {
+            Progress::Joined(request) => request,
+            _ => panic!("The client should have joined")
+        };
+        handshake.deregister(handshake_poll.registry()).unwrap();
+
+        room.room.add_client(handshake.into_stream(), request, &room.poll).unwrap();
+        room.serve();
+        receive_join(&bob, Codec::default(), &["bob", "alice"]);
+        assert!(matches!(receive(&bob, Codec::default()),
+                         ChatMessage::RelayedText { sender, text, .. } if sender == "alice" && text == "hello everyone"));
+    }
+
+    #[test]
+    fn busy_client_is_read_a_batch_at_a_time() {
+        let mut room = TestRoom::new();
+        let alice = room.join("alice", Capabilities::NONE);
+        let bob = room.join("bob", Capabilities::NONE);
+        room.serve();
+        receive_join(&bob, Codec::default(), &["alice", "bob"]);
+
+        let count = 3 * MAX_MESSAGES_PER_READ;
+        let mut data = Vec::new();
+        for index in 0..count {
+            ChatMessage::ChatText { text : index.to_string() }.encode().into_writer(&mut data).unwrap();
+        }
+        (&alice).write_all(&data).unwrap();
+
+        // A single readiness event only reads one batch, the rest waits for the room to broadcast it
+        let mut events = Events::with_capacity(128);
+        room.poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        for event in events.iter() {
+            room.room.look_for_new_messages(event.token(), &room.poll);
+        }
+        assert_eq!(room.room.message_queue.len(), MAX_MESSAGES_PER_READ);
+        assert!(room.room.has_unread_clients());
+
+        room.serve();
+        for index in 0..count {
+            assert!(matches!(receive(&bob, Codec::default()), ChatMessage::RelayedText { text, .. } if text == index.to_string()));
+        }
+        assert!(!room.room.has_unread_clients());
+    }
+}
@@ -1,73 +1,158 @@
-use std::net::TcpStream;
-use tlv_message::message::{AsyncReader, AsyncWriter, Message, AsyncWriteResult, AsyncReadResult};
+use std::io;
+use std::sync::Arc;
+use log::warn;
+use mio::net::TcpStream;
+use mio::{Interest, Registry};
+use tlv_message::codec::Codec;
+use tlv_message::error::{self, Error};
+use tlv_message::frame::{Frame, FrameDecoder};
+use tlv_message::message::{Async, AsyncWriter, LengthLimit, Message};
+use chat_protocol::protocol::{self, Capabilities, ChatMessage};
+use crate::config::Limits;
+use crate::outbound_queue::OutboundQueue;
+use crate::utilities::overflow_counters::OverflowCounters;
 
 pub struct ClientStream {
     stream : TcpStream,
-    async_reader : Option<AsyncReader<Message>>,
-    message_queue : Vec<AsyncWriter<Message>>
+    token : mio::Token,
+    // The name the client chose when joining, unique within the room
+    nickname : String,
+    // The protocol version negotiated with the client
+    version : u16,
+    // The capabilities negotiated with the client
+    capabilities : Capabilities,
+    // How messages are framed once the client is in the room, depends on the capabilities
+    codec : Codec,
+    // Reads into a buffer reused between reads, a single read yields every message the client sent since the last one
+    decoder : FrameDecoder,
+    // Bounds the messages the client may send us
+    length_limit : LengthLimit,
+    // Bounded, so a client which stopped reading can't exhaust our memory
+    message_queue : OutboundQueue,
+    // Whether the stream is registered for write readiness, which is only needed while messages are pending
+    writable_interest : bool
 }
 
 impl ClientStream {
-    pub fn build(stream : TcpStream) -> ClientStream {
+    pub fn build(stream : TcpStream, token : mio::Token, nickname : String, version : u16, capabilities : Capabilities,
+                 limits : Limits, overflow_counters : Arc<OverflowCounters>) -> ClientStream {
         ClientStream {
             stream,
-            async_reader : None,
-            message_queue : Vec::new()
+            token,
+            nickname,
+            version,
+            capabilities,
+            codec : protocol::room_codec(capabilities),
+            decoder : FrameDecoder::new(protocol::room_codec(capabilities), limits.length_limit()),
+            length_limit : limits.length_limit(),
+            message_queue : OutboundQueue::new(limits, overflow_counters),
+            writable_interest : false
         }
     }
 
-    pub fn write_messages_to_stream(&mut self, mut messages: Vec<AsyncWriter<Message>>) {
-        // Queue all messages in our message queue. This is done as we want to handle messages from previous calls first
-        self.queue_messages(messages);
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
 
-        let mut incomplete_message_queue =  Vec::<AsyncWriter<Message>>::new();
+    pub fn version(&self) -> u16 {
+        self.version
+    }
 
-        while let Some(mut message) = self.message_queue.pop() {
-            // Write as much as we can without sleeping
-            while !message.done() {
-                message.async_write(&mut self.stream);
-            }
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
 
-            // Check everything went as expected
-            // TODO: at the moment just crash, later on we will want to remove stream from the room as we are out of sync
-            let result = message.finish().expect("failed to write message to client");
+    /// Register the stream in the poll, so the room will be woken up whenever the client sends us something
+    pub fn register(&mut self, registry : &Registry) -> io::Result<()> {
+        registry.register(&mut self.stream, self.token, Interest::READABLE)
+    }
+
+    pub fn deregister(&mut self, registry : &Registry) -> io::Result<()> {
+        registry.deregister(&mut self.stream)
+    }
 
-            // Check the result, if we didn't send all of the message we will want to continue it on the next loop
-            if let AsyncWriteResult::NotReady(message) = result {
-                incomplete_message_queue.push(message);
+    /// Write as many of the pending messages as the stream accepts without blocking, the rest are written once the
+    /// stream becomes writable again.
+    /// An error means the client is no longer reachable, and should be removed
+    pub fn write_messages_to_stream(&mut self, messages: Vec<AsyncWriter<Message>>, registry : &Registry) -> io::Result<()> {
+        // Messages from previous calls are still in the queue ahead of these, so they are written first
+        for message in messages {
+            if let Err(err) = self.message_queue.push(message) {
+                self.disconnect_slow_consumer();
+                return Err(err);
             }
         }
 
-        self.message_queue = incomplete_message_queue;
+        self.write_pending_messages(registry)
+    }
+
+    /// Continue writing the pending messages, should be called once the stream becomes writable
+    pub fn write_pending_messages(&mut self, registry : &Registry) -> io::Result<()> {
+        self.message_queue.write_to(&mut self.stream)?;
+        self.update_interest(registry)
+    }
+
+    /// Send a message directly to this client only
+    pub fn send(&mut self, message : &ChatMessage, registry : &Registry) -> io::Result<()> {
+        let message = message.encode_with_codec(self.codec)?;
+        self.write_messages_to_stream(vec![AsyncWriter::new(message)], registry)
     }
 
-    fn read_async_from_stream(&mut self) {
-        let receiver = self.async_reader.get_or_insert_with(|| { AsyncReader::<Message>::new() });
-        while !receiver.done() {
-            receiver.async_read(&mut self.stream);
+    // Only ask for write readiness while some messages are waiting for the stream, as it would wake us for nothing otherwise
+    fn update_interest(&mut self, registry : &Registry) -> io::Result<()> {
+        let pending = !self.message_queue.is_empty();
+        if pending != self.writable_interest {
+            let interest = if pending { Interest::READABLE | Interest::WRITABLE } else { Interest::READABLE };
+            registry.reregister(&mut self.stream, self.token, interest)?;
+            self.writable_interest = pending;
         }
+
+        Ok(())
     }
 
-    pub fn read_message(&mut self) -> Option<Message> {
-        self.read_async_from_stream();
-
-        let mut async_reader = self.async_reader.take();
-        let mut result = async_reader.unwrap() // We know it is safe, as we just made sure to put a value inside of inside read_async_from_stream
-            .finish().expect("failed to read from connection");
-
-        match result {
-            AsyncReadResult::NotReady(async_reader) => {
-                self.async_reader = Some(async_reader);
-                None
-            },
-            AsyncReadResult::Ready(message) => {
-                //message_queue.push(AsyncWriter::<Message>::new(message));
-                Some(message)
+    /// Read the next message from the stream, returns None if the message hasn't fully arrived yet.
+    /// The stream is only read once the messages already buffered were handled.
+    /// An error means the client closed the stream, sent an invalid message, or is no longer reachable
+    pub fn read_message(&mut self) -> error::Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return self.decompress(frame).map(Some);
+            }
+
+            match self.decoder.read_from(&mut self.stream)? {
+                Async::NotReady => return Ok(None),
+                Async::Ready(0) => return Err(Error::PeerClosed),
+                Async::Ready(_) => {}
             }
         }
     }
 
-    fn queue_messages(&mut self, mut messages: Vec<AsyncWriter<Message>>) {
-        self.message_queue.append(&mut messages);
+    // Compressed messages are only accepted from clients which negotiated compression
+    fn decompress(&self, frame : Frame) -> error::Result<Frame> {
+        if frame.message_type() & self.codec.compression_flag() == 0 {
+            return Ok(frame);
+        }
+
+        if !self.capabilities.contains(Capabilities::COMPRESSION) {
+            return Err(Error::ProtocolViolation("Compressed message, although compression wasn't negotiated".to_string()));
+        }
+
+        // Compressed messages are rare enough for the copy into a message not to matter
+        Ok(Frame::from(frame.to_message()?.decompress(&self.length_limit)?))
+    }
+
+    /// Let a slow client know why it is being removed.
+    /// This is only a best effort, the client most likely isn't reading anymore
+    fn disconnect_slow_consumer(&mut self) {
+        warn!("Disconnecting {}, it doesn't read its messages fast enough", self.nickname);
+        let error = ChatMessage::Error { reason : "Disconnected for not reading messages fast enough".to_string() };
+        if let Ok(error) = error.encode_with_codec(self.codec) {
+            self.message_queue.replace_pending(AsyncWriter::new(error));
+        }
+        let _ = self.message_queue.write_to(&mut self.stream);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,271 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use clap::{App, Arg, value_t};
+use chat_protocol::protocol::{MAX_NICKNAME_LENGTH, RELAYED_TEXT_OVERHEAD};
+use log::LevelFilter;
+use serde::Deserialize;
+use tlv_message::codec::{CHECKSUM_LENGTH, MAX_HEADER_LENGTH};
+use tlv_message::compression::{self, Compression};
+use tlv_message::message::LengthLimit;
+
+/// The server configuration.
+/// Values are taken from the command line, then from the configuration file (if one was given), then from the defaults
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The addresses to listen on, all of them share the same port
+    pub addresses : Vec<String>,
+    pub port : u16,
+    /// The maximal number of rooms open at the same time
+    pub max_rooms : usize,
+    /// The number of threads serving the rooms, each open room occupies one of them
+    pub pool_size : usize,
+    /// One of off, error, warn, info, debug or trace
+    pub log_level : String,
+    pub limits : Limits
+}
+
+/// Limits applied to each of the clients
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// How long a new client has to complete the whole handshake, from connecting until it asks to join a room
+    pub handshake_timeout_seconds : u64,
+    /// The maximal payload length of a message sent by a client, larger messages get the client disconnected
+    pub max_message_length : usize,
+    /// The maximal number of messages waiting to be sent to a client
+    pub max_queued_messages : usize,
+    /// The maximal number of bytes waiting to be sent to a client
+    pub max_queued_bytes : usize,
+    /// What to do with a client which doesn't read its messages fast enough
+    pub overflow_policy : OverflowPolicy,
+    /// Payloads of at least this many bytes are compressed for the clients which support it
+    pub compression_threshold : usize
+}
+
+/// What to do when a client's outbound queue is full
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest messages the client didn't start receiving yet
+    DropOldest,
+    /// Drop the messages which don't fit in the queue
+    DropNewest,
+    /// Send the client an error and remove it from the room
+    Disconnect
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            addresses : vec!["127.0.0.1".to_string()],
+            // Stay away from privileged ports, so the server doesn't have to run as root
+            port : 7878,
+            max_rooms : 16,
+            pool_size : 16,
+            log_level : "info".to_string(),
+            limits : Limits::default()
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            handshake_timeout_seconds : 5,
+            max_message_length : 64 * 1024,
+            max_queued_messages : 1024,
+            max_queued_bytes : 1024 * 1024,
+            overflow_policy : OverflowPolicy::DropOldest,
+            compression_threshold : compression::DEFAULT_THRESHOLD
+        }
+    }
+}
+
+impl Config {
+    /// Build the configuration from the command line arguments, exits the process on invalid arguments
+    pub fn from_args() -> io::Result<Config> {
+        let matches = App::new("chat_server")
+            .about("A multi room chat server")
+            .arg(Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("TOML configuration file, command line arguments take precedence over it"))
+            .arg(Arg::with_name("address")
+                .short("a")
+                .long("address")
+                .value_name("ADDRESS")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Address to listen on, can be given multiple times"))
+            .arg(Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .value_name("PORT")
+                .help("Port to listen on"))
+            .arg(Arg::with_name("max-rooms")
+                .long("max-rooms")
+                .value_name("COUNT")
+                .help("Maximal number of rooms open at the same time"))
+            .arg(Arg::with_name("pool-size")
+                .long("pool-size")
+                .value_name("THREADS")
+                .help("Number of threads serving the rooms"))
+            .arg(Arg::with_name("log-level")
+                .short("l")
+                .long("log-level")
+                .value_name("LEVEL")
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .help("Logging verbosity"))
+            .get_matches();
+
+        let mut config = match matches.value_of("config") {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default()
+        };
+
+        if let Some(addresses) = matches.values_of("address") {
+            config.addresses = addresses.map(String::from).collect();
+        }
+
+        if matches.is_present("port") {
+            config.port = value_t!(matches, "port", u16).unwrap_or_else(|e| e.exit());
+        }
+
+        if matches.is_present("max-rooms") {
+            config.max_rooms = value_t!(matches, "max-rooms", usize).unwrap_or_else(|e| e.exit());
+        }
+
+        if matches.is_present("pool-size") {
+            config.pool_size = value_t!(matches, "pool-size", usize).unwrap_or_else(|e| e.exit());
+        }
+
+        if let Some(log_level) = matches.value_of("log-level") {
+            config.log_level = log_level.to_string();
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file<P : AsRef<Path>>(path : P) -> io::Result<Config> {
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|err| invalid(err.to_string()))
+    }
+
+    /// All the socket addresses the server should listen on
+    pub fn listen_addresses(&self) -> Vec<String> {
+        self.addresses.iter().map(|address| {
+            // IPv6 addresses must be wrapped with brackets before appending the port
+            if address.contains(':') {
+                format!("[{}]:{}", address, self.port)
+            } else {
+                format!("{}:{}", address, self.port)
+            }
+        }).collect()
+    }
+
+    pub fn log_level(&self) -> io::Result<LevelFilter> {
+        self.log_level.parse().map_err(|_| invalid(format!("Unknown log level {}", self.log_level)))
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        if self.addresses.is_empty() {
+            return Err(invalid("At least one address to listen on is required".to_string()));
+        }
+
+        if self.pool_size == 0 {
+            return Err(invalid("Pool size must be positive".to_string()));
+        }
+
+        // Rooms never exit, a room beyond the pool size would wait for a thread forever
+        if self.max_rooms == 0 || self.max_rooms > self.pool_size {
+            return Err(invalid(format!("Maximal number of rooms must be between 1 and the pool size ({})", self.pool_size)));
+        }
+
+        if self.limits.max_queued_messages == 0 || self.limits.max_queued_bytes == 0 {
+            return Err(invalid("Outbound queue limits must be positive".to_string()));
+        }
+
+        // Otherwise the queue can't hold a single relay of the longest text a client may send
+        let min_queued_bytes = self.limits.max_message_length + RELAYED_TEXT_OVERHEAD + MAX_NICKNAME_LENGTH
+            + MAX_HEADER_LENGTH + CHECKSUM_LENGTH;
+        if self.limits.max_queued_bytes < min_queued_bytes {
+            return Err(invalid(format!("Outbound queue must hold at least {} bytes for the maximal message length", min_queued_bytes)));
+        }
+
+        self.log_level()?;
+        Ok(())
+    }
+}
+
+impl Limits {
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout_seconds)
+    }
+
+    pub fn length_limit(&self) -> LengthLimit {
+        LengthLimit::new(self.max_message_length)
+    }
+
+    pub fn compression(&self) -> Compression {
+        Compression::new(self.compression_threshold)
+    }
+}
+
+fn invalid(reason : String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let invalid_configs : Vec<fn(&mut Config)> = vec![
+            |config| config.addresses.clear(),
+            |config| config.pool_size = 0,
+            |config| config.max_rooms = 0,
+            |config| config.max_rooms = config.pool_size + 1,
+            |config| config.limits.max_queued_messages = 0,
+            |config| config.limits.max_queued_bytes = config.limits.max_message_length,
+            |config| config.log_level = "verbose".to_string()
+        ];
+
+        for make_invalid in invalid_configs {
+            let mut config = Config::default();
+            make_invalid(&mut config);
+            assert_eq!(config.validate().unwrap_err().kind(), io::ErrorKind::InvalidInput, "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn ipv6_addresses_are_bracketed() {
+        let config = Config {
+            addresses : vec!["127.0.0.1".to_string(), "::1".to_string(), "localhost".to_string()],
+            port : 1234,
+            ..Config::default()
+        };
+
+        assert_eq!(config.listen_addresses(), vec!["127.0.0.1:1234", "[::1]:1234", "localhost:1234"]);
+    }
+
+    #[test]
+    fn file_values_override_the_defaults() {
+        let config : Config = toml::from_str("port = 9000\n[limits]\noverflow_policy = \"disconnect\"").unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.limits.overflow_policy, OverflowPolicy::Disconnect);
+        assert_eq!(config.pool_size, Config::default().pool_size);
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use std::io::{self, Write};
+use std::time::Instant;
+
+use log::info;
+use mio::net::TcpStream;
+use mio::{Interest, Registry};
+use tlv_message::error::Error;
+use tlv_message::message::{AsyncReadResult, AsyncReader, LengthLimit, Message};
+use chat_protocol::protocol::{self, Capabilities, ChatMessage, MAX_NICKNAME_LENGTH, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::config::Limits;
+
+/// The capabilities the server knows how to handle
+pub const SERVER_CAPABILITIES : Capabilities = Capabilities::from_bits(
+    Capabilities::ECHO.bits() | Capabilities::COMPRESSION.bits() | Capabilities::CHECKSUM.bits());
+
+/// Hello and join requests are a few bytes long, so a client can't announce a large one and trickle it in
+const MAX_HANDSHAKE_MESSAGE_LENGTH : usize = 1024;
+
+/// A client which completed the handshake and asked to join a room
+pub struct JoinRequest {
+    pub room : String,
+    pub nickname : String,
+    pub version : u16,
+    pub capabilities : Capabilities
+}
+
+/// The handshake a new client opens with: a Hello/Welcome exchange followed by a join request.
+/// The stream is non blocking, so many clients can be in the middle of their handshake at once,
+/// each of them has to complete the whole handshake before its deadline
+pub struct Handshake {
+    stream : TcpStream,
+    state : State,
+    // Reads a single message at a time, so the bytes the client sends once it joined are left for the room
+    reader : Option<AsyncReader<Message>>,
+    deadline : Instant
+}
+
+enum State {
+    AwaitingHello,
+    AwaitingJoin { version : u16, capabilities : Capabilities }
+}
+
+/// How far a handshake got
+pub enum Progress {
+    /// The client didn't send everything it should yet
+    Pending,
+    /// The client asked to join a room
+    Joined(JoinRequest),
+    /// The client was refused, and was already told why
+    Rejected
+}
+
+impl Handshake {
+    pub fn new(stream : TcpStream, limits : &Limits) -> Handshake {
+        Handshake {
+            stream,
+            state : State::AwaitingHello,
+            reader : None,
+            deadline : Instant::now() + limits.handshake_timeout()
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Register the stream in the poll, so we are woken up whenever the client sends us something
+    pub fn register(&mut self, registry : &Registry, token : mio::Token) -> io::Result<()> {
+        registry.register(&mut self.stream, token, Interest::READABLE)
+    }
+
+    pub fn deregister(&mut self, registry : &Registry) -> io::Result<()> {
+        registry.deregister(&mut self.stream)
+    }
+
+    pub fn into_stream(self) -> TcpStream {
+        self.stream
+    }
+
+    /// Handle everything the client sent so far, should be called whenever the stream is readable.
+    /// Readiness is edge triggered, so we keep reading until the stream has nothing left for us.
+    /// An error means the client failed the handshake, and should be dropped
+    pub fn advance(&mut self) -> io::Result<Progress> {
+        while let Some(message) = self.read_message()? {
+            let progress = match self.state {
+                State::AwaitingHello => self.handle_hello(&message)?,
+                State::AwaitingJoin { version, capabilities } => self.handle_join(&message, version, capabilities)?
+            };
+
+            if !matches!(progress, Progress::Pending) {
+                return Ok(progress);
+            }
+        }
+
+        Ok(Progress::Pending)
+    }
+
+    fn handle_hello(&mut self, message : &Message) -> io::Result<Progress> {
+        // Clients speaking the first revision of the protocol open with a join request, and only understand a join rejection
+        if message.message_type() == u32::from(protocol::message_type::JOIN) {
+            return self.reject(ChatMessage::JoinRejected {
+                reason : format!("Protocol version 1 is no longer supported, please upgrade to version {}", PROTOCOL_VERSION)
+            });
+        }
+
+        let (version, capabilities) = match ChatMessage::decode(message) {
+            Ok(ChatMessage::Hello { version, capabilities }) => (version, capabilities),
+            Ok(other) => return self.reject(error(format!("Expected hello, got a message of type {}", other.message_type()))),
+            Err(err) => return self.reject(error(format!("Invalid hello: {}", err)))
+        };
+
+        if version < MIN_PROTOCOL_VERSION {
+            return self.reject(error(format!("Protocol version {} is not supported, the server speaks versions {} to {}",
+                                             version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)));
+        }
+
+        // A newer client is expected to fall back to our version
+        let version = version.min(PROTOCOL_VERSION);
+        let capabilities = capabilities.negotiate(SERVER_CAPABILITIES);
+        reply(&self.stream, ChatMessage::Welcome { version, capabilities })?;
+        self.state = State::AwaitingJoin { version, capabilities };
+        Ok(Progress::Pending)
+    }
+
+    fn handle_join(&mut self, message : &Message, version : u16, capabilities : Capabilities) -> io::Result<Progress> {
+        let (room, nickname) = match ChatMessage::decode(message) {
+            Ok(ChatMessage::Join { room, nickname }) => (room, nickname),
+            Ok(other) => return self.reject(join_rejected(format!("Expected a join request, got a message of type {}", other.message_type()))),
+            Err(err) => return self.reject(join_rejected(format!("Invalid join request: {}", err)))
+        };
+
+        if room.trim().is_empty() {
+            return self.reject(join_rejected("Room name can't be empty".to_string()));
+        }
+
+        if nickname.trim().is_empty() {
+            return self.reject(join_rejected("Nickname can't be empty".to_string()));
+        }
+
+        // Nicknames are compared byte by byte, and rendered in front of every relayed line.
+        // Padding would let two clients look like the same sender, and line breaks would let a client forge lines
+        if nickname.trim() != nickname {
+            return self.reject(join_rejected("Nickname can't start or end with whitespace".to_string()));
+        }
+
+        if nickname.chars().any(char::is_control) {
+            return self.reject(join_rejected("Nickname can't contain control characters".to_string()));
+        }
+
+        if nickname.len() > MAX_NICKNAME_LENGTH {
+            return self.reject(join_rejected(format!("Nickname can't be longer than {} bytes", MAX_NICKNAME_LENGTH)));
+        }
+
+        Ok(Progress::Joined(JoinRequest { room, nickname, version, capabilities }))
+    }
+
+    // Returns None if the message hasn't fully arrived yet
+    fn read_message(&mut self) -> io::Result<Option<Message>> {
+        let reader = self.reader.get_or_insert_with(|| AsyncReader::with_limit(LengthLimit::new(MAX_HANDSHAKE_MESSAGE_LENGTH)));
+        while !reader.done() {
+            reader.async_read(&mut self.stream);
+        }
+
+        // The reader was just put in place
+        match self.reader.take().unwrap().finish() {
+            Ok(AsyncReadResult::NotReady(reader)) => {
+                self.reader = Some(reader);
+                Ok(None)
+            },
+            Ok(AsyncReadResult::Ready(message)) => Ok(Some(message)),
+            Err(err @ Error::FrameTooLarge { .. }) => {
+                // The rest of the message is never read, so the client can't be kept. Let it know why
+                reply(&self.stream, ChatMessage::Error { reason : err.to_string() })?;
+                Err(err.into())
+            },
+            Err(err) => Err(err.into())
+        }
+    }
+
+    fn reject(&self, message : ChatMessage) -> io::Result<Progress> {
+        info!("Rejecting client: {:?}", message);
+        reply(&self.stream, message)?;
+        Ok(Progress::Rejected)
+    }
+}
+
+/// Send a single message to a client which isn't part of a room yet.
+/// The replies are a few bytes long and nothing else was written to the stream, so its send buffer has room for them,
+/// and they are written right away even when the stream is non blocking
+pub fn reply<W : Write>(mut writer : W, message : ChatMessage) -> io::Result<()> {
+    message.encode().into_writer(&mut writer).map_err(io::Error::from)
+}
+
+fn error(reason : String) -> ChatMessage {
+    ChatMessage::Error { reason }
+}
+
+fn join_rejected(reason : String) -> ChatMessage {
+    ChatMessage::JoinRejected { reason }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{self, TcpListener};
+    use std::time::Duration;
+    use mio::{Events, Poll};
+    use super::*;
+
+    // A handshake with the server end of a loopback connection, and the client end
+    fn connect() -> (Handshake, net::TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        (Handshake::new(TcpStream::from_std(server), &Limits::default()), client)
+    }
+
+    // Send all the messages in a single write
+    fn send(mut client : &net::TcpStream, messages : &[ChatMessage]) {
+        let mut data = Vec::new();
+        for message in messages {
+            message.encode().into_writer(&mut data).unwrap();
+        }
+        client.write_all(&data).unwrap();
+    }
+
+    fn receive(mut client : &net::TcpStream) -> ChatMessage {
+        ChatMessage::decode(&Message::from_reader(&mut client).unwrap()).unwrap()
+    }
+
+    // Wait for the client's messages to arrive, and handle them
+    fn advance(handshake : &mut Handshake) -> Progress {
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(1);
+        handshake.register(poll.registry(), mio::Token(0)).unwrap();
+        poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+        let progress = handshake.advance().unwrap();
+        handshake.deregister(poll.registry()).unwrap();
+        progress
+    }
+
+    #[test]
+    fn unsupported_version_is_rejected() {
+        let (mut handshake, client) = connect();
+        send(&client, &[ChatMessage::Hello { version : MIN_PROTOCOL_VERSION - 1, capabilities : Capabilities::NONE }]);
+
+        assert!(matches!(advance(&mut handshake), Progress::Rejected));
+        assert!(matches!(receive(&client), ChatMessage::Error { .. }));
+    }
+
+    #[test]
+    fn protocol_1_client_is_rejected_with_a_join_rejection() {
+        let (mut handshake, client) = connect();
+        send(&client, &[ChatMessage::Join { room : "lobby".to_string(), nickname : "alice".to_string() }]);
+
+        assert!(matches!(advance(&mut handshake), Progress::Rejected));
+        assert!(matches!(receive(&client), ChatMessage::JoinRejected { .. }));
+    }
+
+    #[test]
+    fn newer_client_falls_back_to_our_version() {
+        let (mut handshake, client) = connect();
+        let capabilities = Capabilities::ECHO | Capabilities::HISTORY_REPLAY | Capabilities::from_bits(1 << 31);
+        send(&client, &[ChatMessage::Hello { version : PROTOCOL_VERSION + 1, capabilities }]);
+
+        assert!(matches!(advance(&mut handshake), Progress::Pending));
+        assert_eq!(receive(&client), ChatMessage::Welcome { version : PROTOCOL_VERSION, capabilities : Capabilities::ECHO });
+
+        send(&client, &[ChatMessage::Join { room : "lobby".to_string(), nickname : "alice".to_string() }]);
+        match advance(&mut handshake) {
+            Progress::Joined(request) => {
+                assert_eq!(request.version, PROTOCOL_VERSION);
+                assert_eq!(request.capabilities, Capabilities::ECHO);
+            },
+            _ => panic!("The client should have joined")
+        }
+    }
+
+    #[test]
+    fn misleading_nicknames_are_rejected() {
+        for nickname in &["alice ", "\talice", "alice\nbob: hi", "al\u{7}ice"] {
+            let (mut handshake, client) = connect();
+            send(&client, &[
+                ChatMessage::Hello { version : PROTOCOL_VERSION, capabilities : Capabilities::NONE },
+                ChatMessage::Join { room : "lobby".to_string(), nickname : nickname.to_string() }
+            ]);
+
+            assert!(matches!(advance(&mut handshake), Progress::Rejected), "{:?}", nickname);
+            assert!(matches!(receive(&client), ChatMessage::Welcome { .. }));
+            assert!(matches!(receive(&client), ChatMessage::JoinRejected { .. }));
+        }
+    }
+}
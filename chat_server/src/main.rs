@@ -1,10 +1,8 @@
-#![feature(wait_until)]
-
-use std::net::{TcpListener};
-use std::{io, thread};
-use std::sync::mpsc;
-use std::net::{TcpStream};
+use std::io;
+use std::sync::Arc;
 
+use log::info;
+use utilities::overflow_counters::OverflowCounters;
 use utilities::work_token::Token;
 
 mod chat_room;
@@ -12,32 +10,40 @@ mod room_manager;
 mod utilities;
 mod server;
 mod client;
+mod outbound_queue;
+mod handshake;
+mod config;
 
-use crate::room_manager::{RoomManager, RoomManagerHandler};
+use crate::config::Config;
+use crate::room_manager::RoomManagerHandler;
 
 fn set_signal_handlers(token : Token) {
     ctrlc::set_handler(move || {
+        // Canceling the token wakes up the server and the chat rooms, so they can shutdown
         token.cancel();
-        // Connect to the server in order to release its blocking listener.
-        // This is an ugly but practical workaround, as I don't want to introduce an OS dependent polling system (such as epoll)
-        TcpStream::connect("127.0.0.1:80").unwrap();
     }).expect("Error setting Ctrl-C handler");
 }
 
 fn main() -> io::Result<()> {
+    let config = Config::from_args()?;
+    env_logger::Builder::new().filter_level(config.log_level()?).init();
     // Create token which will be used to shutdown the server cleanly
     let token = Token::build();
     // Setup a signal handler in order to allow shutting down the server with Ctrl+C
     set_signal_handlers(token.clone());
     // Create a channel to distribute TcpStreams from the server to the chat room manager
-    let (tx, rx) = mpsc::channel();
+    let (tx, rx) = room_manager::channel()?;
     // Spin up the chat room handler in a new thread
-    let manager_handler = RoomManagerHandler::spawn(rx, 16, token.clone());
+    let overflow_counters = Arc::new(OverflowCounters::default());
+    let manager_handler = RoomManagerHandler::spawn(rx, config.pool_size, config.max_rooms, config.limits.clone(),
+                                                    overflow_counters.clone(), token.clone());
     // Create the TCP server
-    let server = server::Server::new("127.0.0.1:80", tx)?;
+    let server = server::Server::new(&config.listen_addresses(), tx)?;
     // Listen for new connection as long as token is available
-    server.accept_while_token_available(token.clone());
+    server.accept_while_token_available(token.clone())?;
     // Wait for the chat room manager to close up cleanly
     let _result = manager_handler.join();
+    info!("Slow clients: {} oldest messages dropped, {} new messages dropped, {} clients disconnected",
+          overflow_counters.dropped_oldest(), overflow_counters.dropped_newest(), overflow_counters.disconnected());
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use tlv_message::message::{AsyncWriter, Message};
+use crate::config::{Limits, OverflowPolicy};
+use crate::utilities::overflow_counters::OverflowCounters;
+
+/// The messages waiting to be sent to a single client.
+/// Messages are written in the order they were queued, a partially written message is always completed before the next
+/// one starts, so the client never sees interleaved frames.
+/// The queue is bounded, the overflow policy decides what happens once it is full
+pub struct OutboundQueue {
+    messages : VecDeque<AsyncWriter<Message>>,
+    // The number of bytes in the queue which weren't written yet
+    queued_bytes : usize,
+    limits : Limits,
+    overflow_counters : Arc<OverflowCounters>
+}
+
+impl OutboundQueue {
+    pub fn new(limits : Limits, overflow_counters : Arc<OverflowCounters>) -> OutboundQueue {
+        OutboundQueue {
+            messages : VecDeque::new(),
+            queued_bytes : 0,
+            limits,
+            overflow_counters
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Queue a message, applying the overflow policy when the queue is full.
+    /// An error means the client should be disconnected
+    pub fn push(&mut self, message : AsyncWriter<Message>) -> io::Result<()> {
+        let size = unwritten_bytes(&message);
+        // Dropping older messages would never make room for it
+        if size > self.limits.max_queued_bytes {
+            self.overflow_counters.record(OverflowPolicy::DropNewest);
+            return Ok(());
+        }
+
+        while self.is_full(size) {
+            let policy = self.limits.overflow_policy;
+            match policy {
+                OverflowPolicy::DropOldest => {
+                    // A partially written message can't be dropped, the client would lose track of the stream
+                    match self.messages.iter().position(|queued| queued.bytes_written() == 0) {
+                        Some(index) => {
+                            // Position was just found, so the message is there
+                            let dropped = self.messages.remove(index).unwrap();
+                            self.queued_bytes -= unwritten_bytes(&dropped);
+                        },
+                        // Nothing left to drop, the message is too large for the queue on its own
+                        None => break
+                    }
+                },
+                OverflowPolicy::DropNewest => break,
+                OverflowPolicy::Disconnect => {
+                    self.overflow_counters.record(policy);
+                    return Err(io::Error::other("Client doesn't keep up with the room"));
+                }
+            }
+
+            self.overflow_counters.record(policy);
+        }
+
+        if self.is_full(size) {
+            self.overflow_counters.record(OverflowPolicy::DropNewest);
+        } else {
+            self.queued_bytes += size;
+            self.messages.push_back(message);
+        }
+
+        Ok(())
+    }
+
+    /// Discard the messages the client didn't start receiving, and queue the given message instead, regardless of the limits
+    pub fn replace_pending(&mut self, message : AsyncWriter<Message>) {
+        self.messages.retain(|queued| queued.bytes_written() > 0);
+        self.messages.push_back(message);
+        self.queued_bytes = self.messages.iter().map(unwritten_bytes).sum();
+    }
+
+    /// Write as many messages as the writer accepts without blocking, in order.
+    /// A failure leaves the stream out of sync, so the client can't be kept after an error
+    pub fn write_to<W : Write>(&mut self, writer : &mut W) -> io::Result<()> {
+        let result = self.write_messages(writer);
+        self.queued_bytes = self.messages.iter().map(unwritten_bytes).sum();
+        result
+    }
+
+    fn write_messages<W : Write>(&mut self, writer : &mut W) -> io::Result<()> {
+        // Queued messages go out together in vectored writes, a partially written message stays at the front,
+        // so it is the first one written the next time
+        let written = AsyncWriter::write_batch(self.messages.make_contiguous(), writer)?;
+        self.messages.drain(..written);
+
+        Ok(())
+    }
+
+    // Whether adding a message of the given size would exceed the queue limits
+    fn is_full(&self, size : usize) -> bool {
+        self.messages.len() >= self.limits.max_queued_messages || self.queued_bytes + size > self.limits.max_queued_bytes
+    }
+}
+
+fn unwritten_bytes(message : &AsyncWriter<Message>) -> usize {
+    message.buffer().encoded_length() - message.bytes_written()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A writer which only accepts a limited number of bytes before it would block
+    struct ChokedWriter {
+        output : Vec<u8>,
+        budget : usize
+    }
+
+    impl Write for ChokedWriter {
+        fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+            if self.budget == 0 && !buf.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "no room left"));
+            }
+
+            let bytes = buf.len().min(self.budget);
+            self.output.extend_from_slice(&buf[..bytes]);
+            self.budget -= bytes;
+            Ok(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn queue(max_queued_messages : usize, overflow_policy : OverflowPolicy) -> OutboundQueue {
+        let limits = Limits { max_queued_messages, overflow_policy, ..Limits::default() };
+        OutboundQueue::new(limits, Arc::new(OverflowCounters::default()))
+    }
+
+    fn message(id : u8) -> AsyncWriter<Message> {
+        AsyncWriter::new(Message::new(1, 3, vec![id; 3]))
+    }
+
+    fn received_ids(output : &[u8]) -> Vec<u8> {
+        let mut reader = Cursor::new(output);
+        let mut ids = Vec::new();
+        while (reader.position() as usize) < output.len() {
+            ids.push(Message::from_reader(&mut reader).unwrap().data()[0]);
+        }
+        ids
+    }
+
+    #[test]
+    fn messages_are_written_in_order() {
+        let mut queue = queue(16, OverflowPolicy::DropOldest);
+        let mut writer = ChokedWriter { output : Vec::new(), budget : usize::MAX };
+        for id in 0..5 {
+            queue.push(message(id)).unwrap();
+        }
+
+        queue.write_to(&mut writer).unwrap();
+
+        assert_eq!(queue.queued_bytes, 0);
+        assert_eq!(received_ids(&writer.output), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn partial_message_is_completed_before_the_next_one() {
+        let mut queue = queue(16, OverflowPolicy::DropOldest);
+        let mut writer = ChokedWriter { output : Vec::new(), budget : 0 };
+        queue.push(message(0)).unwrap();
+        queue.push(message(1)).unwrap();
+
+        // Every message is 9 bytes long, stop in the middle of each of them in turn
+        for budget in &[4, 9, 2] {
+            writer.budget = *budget;
+            queue.write_to(&mut writer).unwrap();
+            queue.push(message(writer.output.len() as u8)).unwrap();
+        }
+
+        writer.budget = usize::MAX;
+        queue.write_to(&mut writer).unwrap();
+
+        assert_eq!(received_ids(&writer.output), vec![0, 1, 4, 13, 15]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_partial_message() {
+        let mut queue = queue(2, OverflowPolicy::DropOldest);
+        let mut writer = ChokedWriter { output : Vec::new(), budget : 4 };
+        queue.push(message(0)).unwrap();
+        queue.write_to(&mut writer).unwrap();
+
+        for id in 1..4 {
+            queue.push(message(id)).unwrap();
+        }
+
+        writer.budget = usize::MAX;
+        queue.write_to(&mut writer).unwrap();
+
+        assert_eq!(received_ids(&writer.output), vec![0, 3]);
+        assert_eq!(queue.overflow_counters.dropped_oldest(), 2);
+    }
+
+    #[test]
+    fn oversized_message_is_dropped_alone() {
+        let limits = Limits { max_queued_bytes : 100, overflow_policy : OverflowPolicy::DropOldest, ..Limits::default() };
+        let mut queue = OutboundQueue::new(limits, Arc::new(OverflowCounters::default()));
+        for id in 0..5 {
+            queue.push(message(id)).unwrap();
+        }
+
+        queue.push(AsyncWriter::new(Message::new(1, 200, vec![5; 200]))).unwrap();
+
+        let mut writer = ChokedWriter { output : Vec::new(), budget : usize::MAX };
+        queue.write_to(&mut writer).unwrap();
+        assert_eq!(received_ids(&writer.output), vec![0, 1, 2, 3, 4]);
+        assert_eq!((queue.overflow_counters.dropped_oldest(), queue.overflow_counters.dropped_newest()), (0, 1));
+    }
+
+    #[test]
+    fn drop_newest_and_disconnect() {
+        let mut queue_dropping = queue(2, OverflowPolicy::DropNewest);
+        let mut queue_disconnecting = queue(2, OverflowPolicy::Disconnect);
+        for id in 0..2 {
+            queue_dropping.push(message(id)).unwrap();
+            queue_disconnecting.push(message(id)).unwrap();
+        }
+
+        queue_dropping.push(message(2)).unwrap();
+        assert!(queue_disconnecting.push(message(2)).is_err());
+
+        let mut writer = ChokedWriter { output : Vec::new(), budget : usize::MAX };
+        queue_dropping.write_to(&mut writer).unwrap();
+        assert_eq!(received_ids(&writer.output), vec![0, 1]);
+        assert_eq!(queue_dropping.overflow_counters.dropped_newest(), 1);
+        assert_eq!(queue_disconnecting.overflow_counters.disconnected(), 1);
+    }
+}
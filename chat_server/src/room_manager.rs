@@ -1,22 +1,68 @@
-use std::net::{TcpStream};
 use std::{io, thread};
-use std::io::{BufRead, BufReader};
 use threadpool::ThreadPool;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
 use std::collections::HashMap;
-use std::io::{Error, ErrorKind};
+use std::time::Instant;
 
+use log::{debug, error, info, warn};
+use mio::net::TcpStream;
+use mio::{Events, Poll, Waker};
+use chat_protocol::protocol::ChatMessage;
+use crate::config::Limits;
+use crate::utilities::overflow_counters::OverflowCounters;
 use crate::utilities::work_token::Token;
-use crate::chat_room;
+use crate::chat_room::{self, RoomSender};
+use crate::handshake::{reply, Handshake, JoinRequest, Progress};
 use std::thread::JoinHandle;
 
+// Token used by the manager's waker, clients in the middle of their handshake are assigned with the tokens following it
+const WAKER : mio::Token = mio::Token(0);
+
+/// Sending half of the room manager.
+/// Wakes the manager's event loop whenever a new connection is accepted
+pub struct ConnectionSender {
+    sender : mpsc::Sender<TcpStream>,
+    waker : Arc<Waker>
+}
+
+/// Receiving half of the room manager, owns the poll driving the handshakes
+pub struct ConnectionReceiver {
+    receiver : mpsc::Receiver<TcpStream>,
+    poll : Poll,
+    waker : Arc<Waker>
+}
+
+/// Create the channel new connections are sent to the room manager over
+pub fn channel() -> io::Result<(ConnectionSender, ConnectionReceiver)> {
+    let (sender, receiver) = mpsc::channel();
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+    Ok((ConnectionSender { sender, waker : waker.clone() }, ConnectionReceiver { receiver, poll, waker }))
+}
+
+impl ConnectionSender {
+    /// Hand a new (non blocking) connection over to the room manager
+    pub fn send(&self, stream : TcpStream) -> io::Result<()> {
+        self.sender.send(stream).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Room manager is closed"))?;
+        self.waker.wake()
+    }
+}
+
 /// Manages the different chat rooms,
-/// Will redirect new connections to the appropriate room
+/// Runs the handshake of new connections, and redirects them to the appropriate room
 pub struct RoomManager {
-    receiver : mpsc::Receiver<TcpStream>,
+    connections : ConnectionReceiver,
+    // Clients which didn't complete their handshake yet
+    handshakes : HashMap<mio::Token, Handshake>,
+    // The token that will be assigned to the next client
+    next_token : usize,
     pool : ThreadPool,
     num_threads : usize,
-    room_list : HashMap<String, mpsc::Sender<TcpStream>>,
+    max_rooms : usize,
+    limits : Limits,
+    overflow_counters : Arc<OverflowCounters>,
+    room_list : HashMap<String, RoomSender>,
 }
 
 /// Very simple wrapper around the room manager. Allow to manage it in dedicate thread
@@ -25,56 +71,160 @@ pub struct RoomManagerHandler {
 }
 
 impl RoomManager {
-    pub fn new(receiver : mpsc::Receiver<TcpStream>, num_threads : usize) -> RoomManager {
+    pub fn new(connections : ConnectionReceiver, num_threads : usize, max_rooms : usize, limits : Limits,
+               overflow_counters : Arc<OverflowCounters>) -> RoomManager {
         RoomManager {
-            receiver,
+            connections,
+            handshakes : HashMap::new(),
+            next_token : WAKER.0 + 1,
             pool : ThreadPool::new(num_threads),
             num_threads,
+            max_rooms,
+            limits,
+            overflow_counters,
             room_list : HashMap::new()
         }
     }
 
     pub fn activate(mut self, cancellation_token : Token) -> io::Result<()> {
-        while let Some(stream) = self.receiver.iter().next() {
-            let connection_token = cancellation_token.clone();
-            self.handle_connection(stream, connection_token)?;
+        // Make sure a cancellation wakes us up, instead of waiting for the next client event
+        cancellation_token.register_waker(self.connections.waker.clone());
+        let mut events = Events::with_capacity(128);
 
-            if cancellation_token.canceled() {
-                break;
+        while !cancellation_token.canceled() {
+            // Wake up in time to drop the clients which don't complete their handshake
+            let timeout = self.handshakes.values().map(Handshake::deadline).min()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if let Err(err) = self.connections.poll.poll(&mut events, timeout) {
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
             }
+
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => self.start_handshakes(),
+                    token => self.advance_handshake(token, &cancellation_token)
+                }
+            }
+
+            self.drop_expired_handshakes();
         }
 
         self.pool.join();
         Ok(())
     }
 
-    fn handle_connection(&mut self, stream: TcpStream, cancellation_token : Token) -> io::Result<()> {
-        println!("handling new connection");
-        let room_name = self.get_room_name(&stream)?;
+    fn start_handshakes(&mut self) {
+        while let Ok(stream) = self.connections.receiver.try_recv() {
+            debug!("handling new connection");
+            let token = mio::Token(self.next_token);
+            self.next_token += 1;
+
+            let mut handshake = Handshake::new(stream, &self.limits);
+            match handshake.register(self.connections.poll.registry(), token) {
+                Ok(()) => {
+                    self.handshakes.insert(token, handshake);
+                },
+                Err(err) => warn!("Failed to handle new connection {}", err)
+            }
+        }
+    }
+
+    fn advance_handshake(&mut self, token : mio::Token, cancellation_token : &Token) {
+        let progress = match self.handshakes.get_mut(&token) {
+            Some(handshake) => handshake.advance(),
+            None => return
+        };
+
+        if let Ok(Progress::Pending) = progress {
+            return;
+        }
+
+        // The handshake is over, the stream either moves to a room or is dropped
+        let mut handshake = match self.handshakes.remove(&token) {
+            Some(handshake) => handshake,
+            None => return
+        };
+        if let Err(err) = handshake.deregister(self.connections.poll.registry()) {
+            warn!("Failed to deregister client {}", err);
+        }
+
+        let result = match progress {
+            Ok(Progress::Joined(request)) => self.dispatch(handshake.into_stream(), request, cancellation_token.clone()),
+            // The client was already told why it was rejected
+            Ok(_) => Ok(()),
+            Err(err) => Err(err)
+        };
+
+        // A single misbehaving client shouldn't bring down the manager
+        if let Err(err) = result {
+            warn!("Failed to handle new connection {}", err);
+        }
+    }
+
+    fn drop_expired_handshakes(&mut self) {
+        let now = Instant::now();
+        let expired : Vec<mio::Token> = self.handshakes.iter()
+            .filter(|(_, handshake)| handshake.deadline() <= now)
+            .map(|(token, _)| *token)
+            .collect();
 
+        for token in expired {
+            info!("Dropping a client which didn't complete its handshake in time");
+            if let Some(mut handshake) = self.handshakes.remove(&token) {
+                if let Err(err) = handshake.deregister(self.connections.poll.registry()) {
+                    warn!("Failed to deregister client {}", err);
+                }
+            }
+        }
+    }
+
+    fn dispatch(&mut self, stream : TcpStream, request : JoinRequest, cancellation_token : Token) -> io::Result<()> {
+        info!("{} asked to join room {} (protocol version {}, capabilities {:?})",
+              request.nickname, request.room, request.version, request.capabilities);
+
+        let room_name = request.room.clone();
         let mut room_dispatch = self.room_list.get(&room_name);
 
         if room_dispatch.is_none() {
-            println!("Room {} not found", room_name);
+            debug!("Room {} not found", room_name);
             room_dispatch = self.create_room(&room_name, cancellation_token);
         }
 
-        room_dispatch.and_then(|rx| {
-            println!("Dispatching new client to room {}", room_name);
-            rx.send(stream).ok()
-        }).ok_or(Error::new(ErrorKind::Other, "Failed to dispatch client to room"))
+        match room_dispatch {
+            Some(room) => {
+                // The room replies to the join request, as only it knows which nicknames are taken
+                debug!("Dispatching new client to room {}", room_name);
+                room.send(stream, request)
+            },
+            None => reply(&stream, ChatMessage::JoinRejected { reason : "The server can't open any more rooms".to_string() })
+        }
     }
 
-    fn create_room(&mut self, room_name: &str, cancellation_token : Token) -> Option<&mpsc::Sender<TcpStream>> {
-        if self.pool.active_count() >= self.num_threads {
-            println!("Thread Pool is full");
+    fn create_room(&mut self, room_name: &str, cancellation_token : Token) -> Option<&RoomSender> {
+        if self.room_list.len() >= self.max_rooms {
+            warn!("Maximal number of rooms reached");
+            None
+        } else if self.room_list.len() >= self.num_threads {
+            // Each room holds a thread until shutdown, and a job queued but not picked up yet isn't counted as active
+            warn!("Thread Pool is full");
             None
         } else {
-            let (tx, rx) = mpsc::channel();
+            let (tx, rx) = match chat_room::channel() {
+                Ok(channel) => channel,
+                Err(err) => {
+                    error!("Failed to create room {}", err);
+                    return None;
+                }
+            };
+            let limits = self.limits.clone();
+            let overflow_counters = self.overflow_counters.clone();
             self.pool.execute(move || {
                 //TODO: Add an exit mechanism
-                if chat_room::chat_room_handler(rx, cancellation_token).is_err() {
-                    println!("Error in chat room handler");
+                if chat_room::chat_room_handler(rx, limits, overflow_counters, cancellation_token).is_err() {
+                    error!("Error in chat room handler");
                 };
             });
 
@@ -82,22 +232,16 @@ impl RoomManager {
             self.room_list.get(room_name)
         }
     }
-
-    fn get_room_name(&self, stream: &TcpStream) -> io::Result<String> {
-        let mut reader = BufReader::new(stream);
-        let mut message = String::new();
-        reader.read_line(&mut message)?;
-        Ok(message)
-    }
 }
 
 impl RoomManagerHandler {
-    pub fn spawn(receiver : mpsc::Receiver<TcpStream>, num_threads : usize, room_manager_token : Token) -> RoomManagerHandler {
+    pub fn spawn(connections : ConnectionReceiver, num_threads : usize, max_rooms : usize, limits : Limits,
+                 overflow_counters : Arc<OverflowCounters>, room_manager_token : Token) -> RoomManagerHandler {
         let handler = thread::spawn(move || {
-            let room_manager = RoomManager::new(receiver, 16);
+            let room_manager = RoomManager::new(connections, num_threads, max_rooms, limits, overflow_counters);
 
             if room_manager.activate(room_manager_token).is_err(){
-                println!("Error in room manager");
+                error!("Error in room manager");
             }
         });
 
@@ -109,4 +253,63 @@ impl RoomManagerHandler {
     pub fn join(self) -> thread::Result<()> {
         self.handler.join()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::{self, TcpListener};
+    use std::time::Duration;
+    use tlv_message::message::Message;
+    use chat_protocol::protocol::{Capabilities, PROTOCOL_VERSION};
+    use super::*;
+
+    // Connect a client to the room manager, returns the client end of the connection
+    fn connect(listener : &TcpListener, connections : &ConnectionSender) -> net::TcpStream {
+        let client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        connections.send(TcpStream::from_std(server)).unwrap();
+        client
+    }
+
+    fn send(mut client : &net::TcpStream, message : ChatMessage) {
+        let mut data = Vec::new();
+        message.encode().into_writer(&mut data).unwrap();
+        client.write_all(&data).unwrap();
+    }
+
+    fn receive(mut client : &net::TcpStream) -> tlv_message::error::Result<ChatMessage> {
+        Message::from_reader(&mut client).map(|message| ChatMessage::decode(&message).unwrap())
+    }
+
+    #[test]
+    fn deadline_covers_the_whole_handshake() {
+        let limits = Limits { handshake_timeout_seconds : 1, ..Limits::default() };
+        let (connections, receiver) = channel().unwrap();
+        let token = Token::build();
+        let manager = RoomManagerHandler::spawn(receiver, 1, 1, limits, Arc::new(OverflowCounters::default()), token.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hello = ChatMessage::Hello { version : PROTOCOL_VERSION, capabilities : Capabilities::NONE };
+        let join = ChatMessage::Join { room : "lobby".to_string(), nickname : "alice".to_string() };
+
+        // Being welcomed doesn't buy the client more time to send its join request
+        let slow_client = connect(&listener, &connections);
+        slow_client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        send(&slow_client, hello.clone());
+        assert!(matches!(receive(&slow_client), Ok(ChatMessage::Welcome { .. })));
+        thread::sleep(Duration::from_millis(1500));
+        send(&slow_client, join.clone());
+        assert!(receive(&slow_client).is_err());
+
+        // The chat client reads everything it needs before connecting, and sends it right away
+        let client = connect(&listener, &connections);
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        send(&client, hello);
+        assert!(matches!(receive(&client), Ok(ChatMessage::Welcome { .. })));
+        send(&client, join);
+        assert!(matches!(receive(&client), Ok(ChatMessage::JoinAccepted)));
+
+        token.cancel();
+        manager.join().unwrap();
+    }
+}
@@ -1,48 +1,91 @@
-use std::net::{TcpListener, TcpStream};
-use std::io::Error;
-use std::sync::mpsc;
+use std::net::TcpListener;
+use std::io::{self, Error};
+use std::sync::Arc;
 
+use log::{error, info};
+use mio::{Events, Interest, Poll, Waker};
+
+use crate::room_manager::ConnectionSender;
 use crate::utilities::work_token::Token;
 
+// Listeners are assigned with the tokens following the waker's token
+const WAKER : mio::Token = mio::Token(0);
+
 /// A TCP Server
 /// Listen to new connection and dispatch them to an handler down the stream
 pub struct Server {
-    listener : TcpListener,
-    dispatcher : mpsc::Sender<TcpStream>,
+    listeners : Vec<mio::net::TcpListener>,
+    poll : Poll,
+    dispatcher : ConnectionSender,
 }
 
 impl Server {
-    /// Create the server, provide an address to listen on, and an handler for the TcpStreams (via a channel)
-    pub fn new<T>(address : T, dispatcher : mpsc::Sender<TcpStream>) -> Result<Server, Error>
+    /// Create the server, provide the addresses to listen on, and an handler for the TcpStreams (via a channel)
+    pub fn new<T>(addresses : &[T], dispatcher : ConnectionSender) -> Result<Server, Error>
         where T : AsRef<str> {
+        let poll = Poll::new()?;
+        let mut listeners = Vec::new();
+
+        for (index, address) in addresses.iter().enumerate() {
+            let listener = TcpListener::bind(address.as_ref())?;
+            listener.set_nonblocking(true)?;
+            let mut listener = mio::net::TcpListener::from_std(listener);
+            poll.registry().register(&mut listener, mio::Token(WAKER.0 + 1 + index), Interest::READABLE)?;
+            info!("Listening on {}", address.as_ref());
+            listeners.push(listener);
+        }
+
         Ok(Server {
-            listener : TcpListener::bind(address.as_ref())?,
+            listeners,
+            poll,
             dispatcher
         })
     }
 
     /// Will accept new connections as long as the token wasn't canceled
-    pub fn accept_while_token_available(self, token : Token) {
-        // Listener is not async, so it will wait during stop,
-        // ideally we would want to turn it to async and use epoll (or relevant alternative)
-        // This is behind the scope of this part of the project, so we will use a very ugly trick/workaround
-        // When stopping the server, we will create a local connection from the server to itself, to release the listener, this can be done in the ctrlc handler.
-
-        for stream in self.listener.incoming() {
-            if token.canceled() {
-                break
+    pub fn accept_while_token_available(mut self, token : Token) -> io::Result<()> {
+        // The token will wake up the poll as soon as it is canceled, so we never block past a cancellation
+        token.register_waker(Arc::new(Waker::new(self.poll.registry(), WAKER)?));
+
+        let mut events = Events::with_capacity(128);
+        while !token.canceled() {
+            if let Err(err) = self.poll.poll(&mut events, None) {
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
             }
 
-            match stream {
-                Ok(connection) => {
-                    println!("New connection");
-                    self.dispatcher.send(connection).unwrap();
-                },
-                Err(err) => println!("Error in incoming connection {}", err),
+            for event in events.iter() {
+                if event.token() != WAKER {
+                    self.accept_pending_connections(event.token().0 - WAKER.0 - 1);
+                }
             }
         }
 
         // Manually drop the dispatcher to make sure it is being dropped before the listener (avoid errors on exit)
         std::mem::drop(self.dispatcher);
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn accept_pending_connections(&mut self, index : usize) {
+        // Readiness is edge triggered, so we have to accept until the listener would block
+        loop {
+            match self.listeners[index].accept() {
+                Ok((connection, address)) => {
+                    info!("New connection from {}", address);
+                    // Down the stream the connection stays non blocking, the handshake runs alongside the others
+                    if let Err(err) = self.dispatcher.send(connection) {
+                        error!("Error in incoming connection {}", err);
+                    }
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    error!("Error in incoming connection {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
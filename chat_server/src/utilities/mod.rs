@@ -1 +1,2 @@
-pub mod work_token;
\ No newline at end of file
+pub mod work_token;
+pub mod overflow_counters;
\ No newline at end of file
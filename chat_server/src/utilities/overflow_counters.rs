@@ -0,0 +1,36 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::OverflowPolicy;
+
+/// Count how often clients' outbound queues overflowed, for each of the overflow policies.
+/// Shared by all the rooms of the server
+#[derive(Debug, Default)]
+pub struct OverflowCounters {
+    dropped_oldest : AtomicU64,
+    dropped_newest : AtomicU64,
+    disconnected : AtomicU64
+}
+
+impl OverflowCounters {
+    pub fn record(&self, policy : OverflowPolicy) {
+        let counter = match policy {
+            OverflowPolicy::DropOldest => &self.dropped_oldest,
+            OverflowPolicy::DropNewest => &self.dropped_newest,
+            OverflowPolicy::Disconnect => &self.disconnected
+        };
+        // The counters are only statistics, they don't synchronize anything
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped_oldest(&self) -> u64 {
+        self.dropped_oldest.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_newest(&self) -> u64 {
+        self.dropped_newest.load(Ordering::Relaxed)
+    }
+
+    pub fn disconnected(&self) -> u64 {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+}
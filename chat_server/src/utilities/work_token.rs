@@ -1,7 +1,8 @@
 use std::sync::{Arc, Mutex, Condvar};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::any::Any;
-use core::borrow::Borrow;
+
+use log::error;
+use mio::Waker;
 
 //TODO:
 // Consider allowing wrapping type inside the token, making it easier to pass it around
@@ -15,6 +16,8 @@ impl Token {
        Token(Arc::new(WorkToken::build()))
     }
 
+    // The server only uses the token for cancellation so far, not to wait for work
+    #[allow(dead_code)]
     pub fn ready(&self) -> bool {
         self.0.ready()
     }
@@ -23,6 +26,7 @@ impl Token {
         self.0.ready()
     }
 
+    #[allow(dead_code)]
     pub fn done(&self) {
         self.0.done()
     }
@@ -31,9 +35,16 @@ impl Token {
         self.0.done()
     }
 
+    #[allow(dead_code)]
     pub fn wait(&self) {
         self.0.wait()
     }
+
+    /// Register a waker which will be woken once the token is done/canceled.
+    /// This allows event loops blocked on a poll to notice the cancellation immediately.
+    pub fn register_waker(&self, waker : Arc<Waker>) {
+        self.0.register_waker(waker)
+    }
 }
 // Mutex is used to sync the changes to the ready state with the condition variable
 // It is required, as otherwise the thread checking the condition variable might miss notification
@@ -44,9 +55,10 @@ impl Token {
 //      but you won't get that guarantee for cross platform code.
 // Although all of the above, it still might be better to use a regular boolean while using locks to read the state.
 //      of course it is usage dependent.
+// The mutex also guards the list of wakers, so a waker can't be registered after the wake up round and be missed.
 pub struct WorkToken {
     ready : AtomicBool,
-    mutex : Mutex<()>,
+    mutex : Mutex<Vec<Arc<Waker>>>,
     cv : Condvar
 }
 
@@ -54,28 +66,49 @@ impl WorkToken {
     pub fn build() -> WorkToken {
         WorkToken {
             ready : AtomicBool::new(false),
-            mutex : Mutex::new(()),
+            mutex : Mutex::new(Vec::new()),
             cv : Condvar::new()
         }
     }
     // We don't promise this will return the latest value, as we are not using a lock.
     // We just guarantee the value returned will be valid(e.g. no changes mid=flight).
     pub fn ready(&self) -> bool {
-        return self.ready.load(Ordering::Relaxed);
+        self.ready.load(Ordering::Relaxed)
     }
 
     pub fn done(&self) {
         // We unwrap as threads should not panic while holding the lock
         let guard = self.mutex.lock().unwrap();
         self.ready.store(true, Ordering::Relaxed);
+        let wakers = guard.clone();
         std::mem::drop(guard);
         self.cv.notify_all();
+
+        for waker in wakers {
+            if let Err(err) = waker.wake() {
+                error!("Failed to wake event loop {}", err);
+            }
+        }
     }
 
+    #[allow(dead_code)]
     pub fn wait(&self) {
         // We unwrap the condition variable and the lock as threads holding the lock shouldn't be panicking.
-        let _guard = self.cv.wait_until(self.mutex.lock().unwrap(), |_| {
-            self.ready.load(Ordering::Relaxed)
+        let _guard = self.cv.wait_while(self.mutex.lock().unwrap(), |_| {
+            !self.ready.load(Ordering::Relaxed)
         }).unwrap();
     }
+
+    pub fn register_waker(&self, waker : Arc<Waker>) {
+        let mut guard = self.mutex.lock().unwrap();
+        if self.ready.load(Ordering::Relaxed) {
+            // We are already done, so no one is going to wake the event loop later on
+            std::mem::drop(guard);
+            if let Err(err) = waker.wake() {
+                error!("Failed to wake event loop {}", err);
+            }
+        } else {
+            guard.push(waker);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,328 @@
+use std::convert::TryFrom;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use crc32fast::Hasher;
+
+use crate::error::{Error, Result};
+
+/// The byte order of the numbers in the message header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Endianness {
+    /// Most significant byte first, also known as network order
+    #[default]
+    Big,
+    Little
+}
+
+/// How a number in the message header is encoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FieldWidth {
+    U8,
+    U16,
+    U32,
+    /// LEB128, small numbers take a single byte. Byte order doesn't apply to it
+    Varint
+}
+
+// A LEB128 encoded u32 takes at most 5 bytes
+const MAX_VARINT_LENGTH : usize = 5;
+
+/// The bytes starting every message when the codec uses sync words
+pub const SYNC_WORD : [u8; 2] = [0xa5, 0x5a];
+
+/// The longest header any codec produces
+pub const MAX_HEADER_LENGTH : usize = SYNC_WORD.len() + 2 * MAX_VARINT_LENGTH;
+
+/// The length of the CRC32 trailer, following the payload when the codec uses checksums
+pub const CHECKSUM_LENGTH : usize = 4;
+
+/// Describes how messages are framed on the wire.
+/// The default codec uses a 2 bytes type and a 4 bytes length in network order, the layout the chat protocol speaks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Codec {
+    endianness : Endianness,
+    type_width : FieldWidth,
+    length_width : FieldWidth,
+    // Whether each message ends with a CRC32 of its header and payload
+    checksum : bool,
+    // Whether each message starts with the sync word
+    sync : bool
+}
+
+/// How far a header being read got
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum HeaderStatus {
+    /// At least this many more bytes are needed
+    Incomplete(usize),
+    Complete { message_type : u32, length : u32, header_length : usize }
+}
+
+impl Default for Codec {
+    fn default() -> Codec {
+        Codec {
+            endianness : Endianness::Big,
+            type_width : FieldWidth::U16,
+            length_width : FieldWidth::U32,
+            checksum : false,
+            sync : false
+        }
+    }
+}
+
+impl Codec {
+    /// The codec for peers using native byte order, e.g. embedded devices
+    pub fn native() -> Codec {
+        if cfg!(target_endian = "little") {
+            Codec::default().with_endianness(Endianness::Little)
+        } else {
+            Codec::default().with_endianness(Endianness::Big)
+        }
+    }
+
+    /// A compact codec for short messages, both the type and the length are varints
+    pub fn compact() -> Codec {
+        Codec::default().with_type_width(FieldWidth::Varint).with_length_width(FieldWidth::Varint)
+    }
+
+    pub fn with_endianness(mut self, endianness : Endianness) -> Codec {
+        self.endianness = endianness;
+        self
+    }
+
+    pub fn with_type_width(mut self, type_width : FieldWidth) -> Codec {
+        self.type_width = type_width;
+        self
+    }
+
+    pub fn with_length_width(mut self, length_width : FieldWidth) -> Codec {
+        self.length_width = length_width;
+        self
+    }
+
+    /// Follow each message with a CRC32 of its header and payload, so corrupted or desynchronized streams are detected
+    pub fn with_checksum(mut self, checksum : bool) -> Codec {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Start each message with a sync word, so a reader can find the next message after losing bytes.
+    /// A sync word can appear in a payload as well, so pair it with checksums to tell real messages apart.
+    /// The blocking readers still fail on the first corrupted byte.
+    #[cfg_attr(feature = "bytes", doc = "[`FrameDecoder`](crate::frame::FrameDecoder) reads from any `Read`, and skips corrupted bytes up to the next message.")]
+    #[cfg_attr(feature = "tokio", doc = "[`MessageCodec`](crate::framed::MessageCodec) skips them as well.")]
+    pub fn with_sync(mut self, sync : bool) -> Codec {
+        self.sync = sync;
+        self
+    }
+
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    pub fn type_width(&self) -> FieldWidth {
+        self.type_width
+    }
+
+    pub fn length_width(&self) -> FieldWidth {
+        self.length_width
+    }
+
+    pub fn checksum(&self) -> bool {
+        self.checksum
+    }
+
+    pub fn sync(&self) -> bool {
+        self.sync
+    }
+
+    /// The number of bytes following the payload
+    pub fn trailer_length(&self) -> usize {
+        if self.checksum { CHECKSUM_LENGTH } else { 0 }
+    }
+
+    /// Encode the trailer of a message into the buffer, returns the trailer length
+    pub(crate) fn encode_trailer(&self, header : &[u8], data : &[u8], buffer : &mut [u8; CHECKSUM_LENGTH]) -> usize {
+        if self.checksum {
+            match self.endianness {
+                Endianness::Big => BigEndian::write_u32(buffer, crc32(header, data)),
+                Endianness::Little => LittleEndian::write_u32(buffer, crc32(header, data))
+            }
+        }
+        self.trailer_length()
+    }
+
+    /// Make sure the trailer matches the header and payload it follows
+    pub(crate) fn verify_trailer(&self, header : &[u8], data : &[u8], trailer : &[u8]) -> Result<()> {
+        if !self.checksum {
+            return Ok(());
+        }
+
+        let expected = match self.endianness {
+            Endianness::Big => BigEndian::read_u32(trailer),
+            Endianness::Little => LittleEndian::read_u32(trailer)
+        };
+        let actual = crc32(header, data);
+        if expected != actual {
+            return Err(Error::ChecksumMismatch { expected, actual });
+        }
+
+        Ok(())
+    }
+
+    /// Encode the header into the buffer, returns the header length.
+    /// Fails if one of the values doesn't fit in its field
+    pub(crate) fn encode_header(&self, message_type : u32, length : u32, buffer : &mut [u8; MAX_HEADER_LENGTH]) -> Result<usize> {
+        let sync_length = self.sync_length();
+        buffer[..sync_length].copy_from_slice(&SYNC_WORD[..sync_length]);
+        let type_length = self.encode_field(self.type_width, message_type, &mut buffer[sync_length..])
+            .ok_or_else(|| Error::MalformedHeader(format!("Type {} doesn't fit in a {:?} field", message_type, self.type_width)))?;
+        let length_length = self.encode_field(self.length_width, length, &mut buffer[(sync_length + type_length)..])
+            .ok_or_else(|| Error::MalformedHeader(format!("Length {} doesn't fit in a {:?} field", length, self.length_width)))?;
+        Ok(sync_length + type_length + length_length)
+    }
+
+    /// Parse the beginning of a header
+    pub(crate) fn decode_header(&self, buffer : &[u8]) -> Result<HeaderStatus> {
+        // Checked as soon as the first byte arrives, so a reader looking for the next message can tell quickly
+        let sync_length = self.sync_length();
+        let available = buffer.len().min(sync_length);
+        if buffer[..available] != SYNC_WORD[..available] {
+            return Err(Error::MalformedHeader("Message doesn't start with the sync word".to_string()));
+        }
+        if available < sync_length {
+            return Ok(HeaderStatus::Incomplete(sync_length - available));
+        }
+
+        let (message_type, type_length) = match self.decode_field(self.type_width, &buffer[sync_length..])? {
+            Ok(field) => field,
+            Err(missing) => return Ok(HeaderStatus::Incomplete(missing))
+        };
+
+        let fields_length = sync_length + type_length;
+        match self.decode_field(self.length_width, &buffer[fields_length..])? {
+            Ok((length, length_length)) => Ok(HeaderStatus::Complete { message_type, length, header_length : fields_length + length_length }),
+            Err(missing) => Ok(HeaderStatus::Incomplete(missing))
+        }
+    }
+
+    fn sync_length(&self) -> usize {
+        if self.sync { SYNC_WORD.len() } else { 0 }
+    }
+
+    // Returns the number of bytes used, or None if the value doesn't fit
+    fn encode_field(&self, width : FieldWidth, value : u32, buffer : &mut [u8]) -> Option<usize> {
+        match width {
+            FieldWidth::U8 => {
+                buffer[0] = u8::try_from(value).ok()?;
+                Some(1)
+            },
+            FieldWidth::U16 => {
+                let value = u16::try_from(value).ok()?;
+                match self.endianness {
+                    Endianness::Big => BigEndian::write_u16(buffer, value),
+                    Endianness::Little => LittleEndian::write_u16(buffer, value)
+                }
+                Some(2)
+            },
+            FieldWidth::U32 => {
+                match self.endianness {
+                    Endianness::Big => BigEndian::write_u32(buffer, value),
+                    Endianness::Little => LittleEndian::write_u32(buffer, value)
+                }
+                Some(4)
+            },
+            FieldWidth::Varint => {
+                let mut value = value;
+                let mut length = 0;
+                loop {
+                    let byte = (value & 0x7f) as u8;
+                    value >>= 7;
+                    if value == 0 {
+                        buffer[length] = byte;
+                        return Some(length + 1);
+                    }
+                    // The high bit marks that more bytes follow
+                    buffer[length] = byte | 0x80;
+                    length += 1;
+                }
+            }
+        }
+    }
+
+    // Returns the value and the number of bytes it used, or the number of bytes still missing
+    fn decode_field(&self, width : FieldWidth, buffer : &[u8]) -> Result<std::result::Result<(u32, usize), usize>> {
+        let size = match width {
+            FieldWidth::U8 => 1,
+            FieldWidth::U16 => 2,
+            FieldWidth::U32 => 4,
+            FieldWidth::Varint => return decode_varint(buffer)
+        };
+
+        if buffer.len() < size {
+            return Ok(Err(size - buffer.len()));
+        }
+
+        let value = match (width, self.endianness) {
+            (FieldWidth::U8, _) => u32::from(buffer[0]),
+            (FieldWidth::U16, Endianness::Big) => u32::from(BigEndian::read_u16(buffer)),
+            (FieldWidth::U16, Endianness::Little) => u32::from(LittleEndian::read_u16(buffer)),
+            (_, Endianness::Big) => BigEndian::read_u32(buffer),
+            (_, Endianness::Little) => LittleEndian::read_u32(buffer)
+        };
+        Ok(Ok((value, size)))
+    }
+}
+
+fn crc32(header : &[u8], data : &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(header);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn decode_varint(buffer : &[u8]) -> Result<std::result::Result<(u32, usize), usize>> {
+    let mut value = 0u32;
+    for (index, byte) in buffer.iter().enumerate() {
+        // The last byte may only hold the 4 remaining bits of a u32
+        if index == MAX_VARINT_LENGTH - 1 && *byte > 0x0f {
+            return Err(Error::MalformedHeader("Varint doesn't fit in 32 bits".to_string()));
+        }
+
+        value |= u32::from(byte & 0x7f) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Ok(Ok((value, index + 1)));
+        }
+    }
+
+    // We can't tell how long the varint is, so read it byte by byte
+    Ok(Err(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_round_trip() {
+        let codec = Codec::compact();
+        for (value, expected_length) in &[(0u32, 1usize), (127, 1), (128, 2), (300, 2), (u32::MAX, 5)] {
+            let mut buffer = [0u8; MAX_HEADER_LENGTH];
+            let length = codec.encode_field(FieldWidth::Varint, *value, &mut buffer).unwrap();
+            assert_eq!(length, *expected_length);
+            assert_eq!(decode_varint(&buffer[..length]).unwrap(), Ok((*value, length)));
+            // Every byte is needed
+            assert_eq!(decode_varint(&buffer[..length - 1]).unwrap(), Err(1));
+        }
+
+        assert!(decode_varint(&[0xff, 0xff, 0xff, 0xff, 0x1f]).is_err());
+    }
+
+    #[test]
+    fn values_must_fit_their_field() {
+        let codec = Codec::default().with_type_width(FieldWidth::U8);
+        let mut buffer = [0u8; MAX_HEADER_LENGTH];
+        assert_eq!(codec.encode_header(255, 70000, &mut buffer).unwrap(), 5);
+        assert!(codec.encode_header(256, 0, &mut buffer).is_err());
+        assert!(Codec::default().encode_header(1 << 16, 0, &mut buffer).is_err());
+    }
+}
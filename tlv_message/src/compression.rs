@@ -0,0 +1,149 @@
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::codec::{Codec, FieldWidth};
+use crate::error::{Error, Result};
+use crate::message::{LengthLimit, Message};
+
+/// Payloads shorter than this are not worth the CPU, deflate barely shrinks them
+pub const DEFAULT_THRESHOLD : usize = 512;
+
+/// When and how hard to compress payloads.
+/// A compressed message is flagged with the top bit of its type, so peers using compression can't use that bit themselves
+#[derive(Clone, Copy, Debug)]
+pub struct Compression {
+    threshold : usize,
+    level : u32
+}
+
+impl Compression {
+    /// Compress payloads of at least threshold bytes
+    pub fn new(threshold : usize) -> Compression {
+        Compression { threshold, level : 6 }
+    }
+
+    /// From 0 (fastest) to 9 (smallest)
+    pub fn with_level(mut self, level : u32) -> Compression {
+        self.level = level.min(9);
+        self
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::new(DEFAULT_THRESHOLD)
+    }
+}
+
+impl Codec {
+    /// The bit in the type field marking a compressed payload, the top bit of the field
+    pub fn compression_flag(&self) -> u32 {
+        match self.type_width() {
+            FieldWidth::U8 => 0x80,
+            FieldWidth::U16 => 0x8000,
+            FieldWidth::U32 | FieldWidth::Varint => 0x8000_0000
+        }
+    }
+}
+
+impl Message {
+    pub fn is_compressed(&self) -> bool {
+        self.message_type() & self.codec().compression_flag() != 0
+    }
+
+    /// The message with its payload compressed, a single compressed message can be sent to many peers.
+    /// None if the payload is below the threshold, or doesn't shrink (e.g. it is compressed already)
+    pub fn compressed(&self, compression : &Compression) -> Result<Option<Message>> {
+        if self.is_compressed() || self.data().len() < compression.threshold {
+            return Ok(None);
+        }
+
+        let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::new(compression.level));
+        encoder.write_all(self.data())?;
+        let data = encoder.finish()?;
+        if data.len() >= self.data().len() {
+            return Ok(None);
+        }
+
+        let message_type = self.message_type() | self.codec().compression_flag();
+        Message::with_codec(self.codec(), message_type, data.len() as u32, data).map(Some)
+    }
+
+    /// The message with its original type and payload, uncompressed messages are returned as they are.
+    /// The payload is inflated up to the type's limit, so a small message can't expand into an arbitrary amount of memory
+    pub fn decompress(self, limit : &LengthLimit) -> Result<Message> {
+        if !self.is_compressed() {
+            return Ok(self);
+        }
+
+        let message_type = self.message_type() & !self.codec().compression_flag();
+        let max_length = limit.max_length(message_type);
+        let mut data = Vec::new();
+        DeflateDecoder::new(self.data()).take((max_length as u64).saturating_add(1)).read_to_end(&mut data)
+            .map_err(|err| Error::ProtocolViolation(format!("Invalid compressed payload: {}", err)))?;
+
+        // Inflating stopped right past the limit, so the reported length is only a lower bound
+        limit.check(message_type, data.len())?;
+        let length = u32::try_from(data.len()).map_err(|_| Error::FrameTooLarge { length : data.len(), max_length })?;
+        Message::with_codec(self.codec(), message_type, length, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(codec : Codec, data : Vec<u8>) -> Message {
+        Message::with_codec(codec, 12, data.len() as u32, data).unwrap()
+    }
+
+    #[test]
+    fn large_payloads_round_trip() {
+        let data = b"a pasted log line, ".repeat(100);
+        for codec in &[Codec::default(), Codec::compact()] {
+            let compressed = message(*codec, data.clone()).compressed(&Compression::default()).unwrap().unwrap();
+            assert!(compressed.is_compressed());
+            assert!(compressed.data().len() < data.len());
+
+            let mut encoded = Vec::new();
+            compressed.write_to(&mut encoded).unwrap();
+            let read = Message::from_reader_with_codec(&mut &encoded[..], *codec, &LengthLimit::default()).unwrap();
+            let decompressed = read.decompress(&LengthLimit::default()).unwrap();
+            assert_eq!((decompressed.message_type(), decompressed.data()), (12, &data[..]));
+        }
+    }
+
+    #[test]
+    fn small_and_incompressible_payloads_are_kept() {
+        let small = message(Codec::default(), b"hi".to_vec());
+        assert!(small.compressed(&Compression::default()).unwrap().is_none());
+        assert_eq!(small.clone().decompress(&LengthLimit::default()).unwrap().data(), b"hi");
+
+        let mut state = 0x2545_f491_u32;
+        let noise : Vec<u8> = (0..2048).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+        assert!(message(Codec::default(), noise).compressed(&Compression::new(0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn inflating_past_the_limit_is_rejected() {
+        let compressed = message(Codec::default(), vec![0; 64 * 1024]).compressed(&Compression::default()).unwrap().unwrap();
+        assert!(compressed.data().len() < 1024);
+        assert!(matches!(compressed.clone().decompress(&LengthLimit::new(1024)), Err(Error::FrameTooLarge { max_length : 1024, .. })));
+        assert_eq!(compressed.clone().decompress(&LengthLimit::new(usize::MAX)).unwrap().data().len(), 64 * 1024);
+
+        let corrupted = Message::with_codec(Codec::default(), compressed.message_type(), 3, vec![0xff; 3]).unwrap();
+        assert!(matches!(corrupted.decompress(&LengthLimit::default()), Err(Error::ProtocolViolation(_))));
+    }
+}
@@ -0,0 +1,88 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while reading or writing messages
+#[derive(Debug)]
+pub enum Error {
+    /// The underlying reader/writer failed
+    Io(io::Error),
+    /// The peer closed the stream in the middle of a message
+    PeerClosed,
+    /// The message is longer than the reader accepts
+    FrameTooLarge { length : usize, max_length : usize },
+    /// The message header can't be parsed
+    MalformedHeader(String),
+    /// The message type is not known, reported by the layers interpreting the messages
+    UnknownType(u32),
+    /// The message doesn't match its checksum, the stream is corrupted or out of sync
+    ChecksumMismatch { expected : u32, actual : u32 },
+    /// A field the payload must have is missing
+    MissingField(u32),
+    /// The message is valid, but not expected at this point of the conversation, or its content is invalid
+    ProtocolViolation(String)
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::PeerClosed => write!(f, "Peer closed the stream"),
+            Error::FrameTooLarge { length, max_length } =>
+                write!(f, "Message of {} bytes is larger than the maximum of {} bytes", length, max_length),
+            Error::MalformedHeader(reason) => write!(f, "Malformed message header: {}", reason),
+            Error::UnknownType(message_type) => write!(f, "Unknown message type {}", message_type),
+            Error::ChecksumMismatch { expected, actual } =>
+                write!(f, "Message checksum {:08x} doesn't match its content, which has checksum {:08x}", expected, actual),
+            Error::MissingField(tag) => write!(f, "Missing field {}", tag),
+            Error::ProtocolViolation(reason) => write!(f, "Protocol violation: {}", reason)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+// io::Error can't be cloned, so the clone keeps what is needed to rebuild it
+impl Clone for Error {
+    fn clone(&self) -> Error {
+        match self {
+            Error::Io(err) => Error::Io(io::Error::new(err.kind(), err.to_string())),
+            Error::PeerClosed => Error::PeerClosed,
+            Error::FrameTooLarge { length, max_length } => Error::FrameTooLarge { length : *length, max_length : *max_length },
+            Error::MalformedHeader(reason) => Error::MalformedHeader(reason.clone()),
+            Error::UnknownType(message_type) => Error::UnknownType(*message_type),
+            Error::ChecksumMismatch { expected, actual } => Error::ChecksumMismatch { expected : *expected, actual : *actual },
+            Error::MissingField(tag) => Error::MissingField(*tag),
+            Error::ProtocolViolation(reason) => Error::ProtocolViolation(reason.clone())
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err : io::Error) -> Error {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            Error::PeerClosed
+        } else {
+            Error::Io(err)
+        }
+    }
+}
+
+// Lets code working with io::Result use the message API with the ? operator
+impl From<Error> for io::Error {
+    fn from(err : Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            Error::PeerClosed => io::Error::new(io::ErrorKind::UnexpectedEof, Error::PeerClosed),
+            err => io::Error::new(io::ErrorKind::InvalidData, err)
+        }
+    }
+}
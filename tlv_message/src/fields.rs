@@ -0,0 +1,295 @@
+use std::convert::TryFrom;
+use std::str;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use crate::codec::{Codec, Endianness, HeaderStatus, MAX_HEADER_LENGTH};
+use crate::error::{Error, Result};
+use crate::message::Message;
+
+/// A single field of a structured payload: a tag, and a value whose meaning depends on the tag
+#[derive(Clone, Copy, Debug)]
+pub struct Field<'a> {
+    codec : Codec,
+    tag : u32,
+    value : &'a [u8]
+}
+
+/// A payload made of a sequence of fields, each of them laid out like a message (tag, length, value).
+/// Fields with unknown tags are kept, so newer peers can add fields without breaking older ones
+#[derive(Clone, Debug)]
+pub struct Fields<'a> {
+    fields : Vec<Field<'a>>
+}
+
+/// Builds a structured payload field by field.
+/// The first failure (e.g. a tag too large for the codec) is reported when building
+pub struct FieldsBuilder {
+    codec : Codec,
+    data : Vec<u8>,
+    error : Option<Error>
+}
+
+impl<'a> Field<'a> {
+    pub fn tag(&self) -> u32 {
+        self.tag
+    }
+
+    pub fn value(&self) -> &'a [u8] {
+        self.value
+    }
+
+    pub fn as_str(&self) -> Result<&'a str> {
+        str::from_utf8(self.value).map_err(|_| invalid(self.tag, "is not valid UTF-8"))
+    }
+
+    /// Numbers are 8 bytes long, in the codec's byte order
+    pub fn as_u64(&self) -> Result<u64> {
+        if self.value.len() != 8 {
+            return Err(invalid(self.tag, "is not a 64 bits number"));
+        }
+
+        Ok(match self.codec.endianness() {
+            Endianness::Big => BigEndian::read_u64(self.value),
+            Endianness::Little => LittleEndian::read_u64(self.value)
+        })
+    }
+
+    /// Parse a nested structure
+    pub fn as_fields(&self) -> Result<Fields<'a>> {
+        Fields::parse_with_codec(self.codec, self.value)
+    }
+}
+
+impl<'a> Fields<'a> {
+    /// Parse a payload using the default codec
+    pub fn parse(data : &'a [u8]) -> Result<Fields<'a>> {
+        Fields::parse_with_codec(Codec::default(), data)
+    }
+
+    pub fn parse_with_codec(codec : Codec, data : &'a [u8]) -> Result<Fields<'a>> {
+        let mut fields = Vec::new();
+        let mut remaining = data;
+
+        // Sync words only start messages, fields inside the payload don't have them
+        let field_codec = codec.with_sync(false);
+        while !remaining.is_empty() {
+            let (tag, length, header_length) = match field_codec.decode_header(remaining)? {
+                HeaderStatus::Complete { message_type, length, header_length } => (message_type, length as usize, header_length),
+                HeaderStatus::Incomplete(_) => return Err(Error::ProtocolViolation("Field header is truncated".to_string()))
+            };
+
+            if remaining.len() - header_length < length {
+                return Err(invalid(tag, "is truncated"));
+            }
+
+            let (value, rest) = remaining[header_length..].split_at(length);
+            fields.push(Field { codec, tag, value });
+            remaining = rest;
+        }
+
+        Ok(Fields { fields })
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Field<'a>> {
+        self.fields.iter()
+    }
+
+    /// The first field with the given tag
+    pub fn get(&self, tag : u32) -> Option<&Field<'a>> {
+        self.fields.iter().find(|field| field.tag == tag)
+    }
+
+    pub fn get_bytes(&self, tag : u32) -> Result<&'a [u8]> {
+        Ok(self.require(tag)?.value())
+    }
+
+    pub fn get_str(&self, tag : u32) -> Result<&'a str> {
+        self.require(tag)?.as_str()
+    }
+
+    pub fn get_u64(&self, tag : u32) -> Result<u64> {
+        self.require(tag)?.as_u64()
+    }
+
+    pub fn get_fields(&self, tag : u32) -> Result<Fields<'a>> {
+        self.require(tag)?.as_fields()
+    }
+
+    fn require(&self, tag : u32) -> Result<&Field<'a>> {
+        self.get(tag).ok_or(Error::MissingField(tag))
+    }
+}
+
+impl<'a, 'b> IntoIterator for &'b Fields<'a> {
+    type Item = &'b Field<'a>;
+    type IntoIter = std::slice::Iter<'b, Field<'a>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl Message {
+    /// Parse the payload as a sequence of fields, using the message's codec
+    pub fn fields(&self) -> Result<Fields<'_>> {
+        Fields::parse_with_codec(self.codec(), self.data())
+    }
+}
+
+impl FieldsBuilder {
+    pub fn new() -> FieldsBuilder {
+        FieldsBuilder::with_codec(Codec::default())
+    }
+
+    pub fn with_codec(codec : Codec) -> FieldsBuilder {
+        FieldsBuilder {
+            codec,
+            data : Vec::new(),
+            error : None
+        }
+    }
+
+    pub fn add_bytes(mut self, tag : u32, value : &[u8]) -> FieldsBuilder {
+        if self.error.is_some() {
+            return self;
+        }
+
+        let mut header = [0; MAX_HEADER_LENGTH];
+        let header_length = u32::try_from(value.len())
+            .map_err(|_| invalid(tag, "is too long"))
+            .and_then(|length| self.codec.with_sync(false).encode_header(tag, length, &mut header));
+
+        match header_length {
+            Ok(header_length) => {
+                self.data.extend_from_slice(&header[..header_length]);
+                self.data.extend_from_slice(value);
+            },
+            Err(err) => self.error = Some(err)
+        }
+        self
+    }
+
+    pub fn add_str(self, tag : u32, value : &str) -> FieldsBuilder {
+        self.add_bytes(tag, value.as_bytes())
+    }
+
+    pub fn add_u64(self, tag : u32, value : u64) -> FieldsBuilder {
+        let mut buffer = [0; 8];
+        match self.codec.endianness() {
+            Endianness::Big => BigEndian::write_u64(&mut buffer, value),
+            Endianness::Little => LittleEndian::write_u64(&mut buffer, value)
+        }
+        self.add_bytes(tag, &buffer)
+    }
+
+    /// Add a nested structure, it must be built with this builder's codec to be parsed back
+    pub fn add_fields(mut self, tag : u32, fields : FieldsBuilder) -> FieldsBuilder {
+        // Sync words and checksums only wrap messages, the fields themselves don't depend on them
+        if field_layout(fields.codec) != field_layout(self.codec) {
+            self.error.get_or_insert(invalid(tag, "is built with a different codec"));
+            return self;
+        }
+
+        match fields.build() {
+            Ok(data) => self.add_bytes(tag, &data),
+            Err(err) => {
+                self.error.get_or_insert(err);
+                self
+            }
+        }
+    }
+
+    /// The encoded payload
+    pub fn build(self) -> Result<Vec<u8>> {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.data)
+        }
+    }
+
+    /// A message holding the payload, using the builder's codec
+    pub fn into_message(self, message_type : u32) -> Result<Message> {
+        let codec = self.codec;
+        let data = self.build()?;
+        let length = u32::try_from(data.len()).map_err(|_| Error::FrameTooLarge { length : data.len(), max_length : u32::MAX as usize })?;
+        Message::with_codec(codec, message_type, length, data)
+    }
+}
+
+impl Default for FieldsBuilder {
+    fn default() -> FieldsBuilder {
+        FieldsBuilder::new()
+    }
+}
+
+fn field_layout(codec : Codec) -> Codec {
+    codec.with_sync(false).with_checksum(false)
+}
+
+fn invalid(tag : u32, reason : &str) -> Error {
+    Error::ProtocolViolation(format!("Field {} {}", tag, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENDER : u32 = 1;
+    const TIMESTAMP : u32 = 2;
+    const ATTACHMENT : u32 = 3;
+    const NAME : u32 = 1;
+
+    #[test]
+    fn fields_round_trip() {
+        for codec in &[Codec::default(), Codec::compact()] {
+            let message = FieldsBuilder::with_codec(*codec)
+                .add_str(SENDER, "alice")
+                .add_u64(TIMESTAMP, 1_600_000_000_000)
+                .add_fields(ATTACHMENT, FieldsBuilder::with_codec(*codec).add_str(NAME, "cat.png"))
+                .into_message(12)
+                .unwrap();
+
+            let fields = message.fields().unwrap();
+            assert_eq!(fields.get_str(SENDER).unwrap(), "alice");
+            assert_eq!(fields.get_u64(TIMESTAMP).unwrap(), 1_600_000_000_000);
+            assert_eq!(fields.get_fields(ATTACHMENT).unwrap().get_str(NAME).unwrap(), "cat.png");
+            assert_eq!(fields.iter().map(Field::tag).collect::<Vec<u32>>(), vec![SENDER, TIMESTAMP, ATTACHMENT]);
+        }
+    }
+
+    #[test]
+    fn nested_fields_must_share_the_codec() {
+        let mixed = FieldsBuilder::with_codec(Codec::compact())
+            .add_fields(ATTACHMENT, FieldsBuilder::new().add_str(NAME, "cat.png"))
+            .build();
+        assert!(matches!(mixed, Err(Error::ProtocolViolation(_))));
+
+        // Only the layout of the fields matters
+        let data = FieldsBuilder::with_codec(Codec::compact().with_checksum(true).with_sync(true))
+            .add_fields(ATTACHMENT, FieldsBuilder::with_codec(Codec::compact()).add_str(NAME, "cat.png"))
+            .build()
+            .unwrap();
+        let fields = Fields::parse_with_codec(Codec::compact(), &data).unwrap();
+        assert_eq!(fields.get_fields(ATTACHMENT).unwrap().get_str(NAME).unwrap(), "cat.png");
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        // A newer peer added a field we don't know about
+        let data = FieldsBuilder::new().add_str(99, "from the future").add_str(SENDER, "bob").build().unwrap();
+
+        let fields = Fields::parse(&data).unwrap();
+        assert_eq!(fields.get_str(SENDER).unwrap(), "bob");
+        assert!(matches!(fields.get_str(TIMESTAMP), Err(Error::MissingField(TIMESTAMP))));
+    }
+
+    #[test]
+    fn malformed_payloads_are_rejected() {
+        let data = FieldsBuilder::new().add_str(SENDER, "alice").build().unwrap();
+        assert!(Fields::parse(&data[..data.len() - 1]).is_err());
+        assert!(Fields::parse(&data[..3]).is_err());
+        assert!(FieldsBuilder::new().add_str(1 << 16, "too large a tag").build().is_err());
+        assert!(Fields::parse(&data).unwrap().get_u64(SENDER).is_err());
+    }
+}
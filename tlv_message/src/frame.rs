@@ -0,0 +1,328 @@
+use std::convert::TryFrom;
+use std::io::Read;
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::codec::{Codec, HeaderStatus, SYNC_WORD};
+use crate::error::{Error, Result};
+use crate::fields::Fields;
+use crate::message::{Async, AsyncRead, AsyncResult, LengthLimit, Message};
+
+/// The default number of bytes the decoder asks for in a single read
+pub const DEFAULT_READ_SIZE : usize = 8 * 1024;
+
+/// A decoded message whose payload is a slice of the decoder's buffer, so decoding it copies nothing
+#[derive(Clone, Debug)]
+pub struct Frame {
+    codec : Codec,
+    message_type : u32,
+    payload : Bytes,
+    skipped : usize
+}
+
+/// Reads into a single buffer which is reused between reads, and splits the frames out of it.
+/// The buffer's memory is reclaimed once all the frames decoded from it are dropped,
+/// so in the steady state reading and decoding doesn't allocate.
+/// With a codec using sync words, corrupted bytes are skipped up to the next frame instead of failing the stream
+pub struct FrameDecoder {
+    codec : Codec,
+    limit : LengthLimit,
+    buffer : BytesMut,
+    read_size : usize,
+    // The number of bytes discarded since the last frame
+    skipped : usize
+}
+
+impl Frame {
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn message_type(&self) -> u32 {
+        self.message_type
+    }
+
+    /// The payload, cloning it only increases a reference count
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    /// The number of corrupted bytes the decoder discarded right before this frame
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Parse the payload as a sequence of fields, borrowing from the frame
+    pub fn fields(&self) -> Result<Fields<'_>> {
+        Fields::parse_with_codec(self.codec, &self.payload)
+    }
+
+    /// An owned message, for code working with messages, this copies the payload
+    pub fn to_message(&self) -> Result<Message> {
+        let length = u32::try_from(self.payload.len()).map_err(|_| Error::FrameTooLarge { length : self.payload.len(), max_length : u32::MAX as usize })?;
+        Message::with_codec(self.codec, self.message_type, length, self.payload.to_vec())
+    }
+}
+
+// Takes over the message's payload without copying it
+impl From<Message> for Frame {
+    fn from(message : Message) -> Frame {
+        Frame {
+            codec : message.codec(),
+            message_type : message.message_type(),
+            payload : Bytes::from(message.into_data()),
+            skipped : 0
+        }
+    }
+}
+
+impl FrameDecoder {
+    pub fn new(codec : Codec, limit : LengthLimit) -> FrameDecoder {
+        FrameDecoder::with_read_size(codec, limit, DEFAULT_READ_SIZE)
+    }
+
+    /// A larger read size means fewer reads when many or large frames arrive at once, at the cost of a larger buffer
+    pub fn with_read_size(codec : Codec, limit : LengthLimit, read_size : usize) -> FrameDecoder {
+        FrameDecoder {
+            codec,
+            limit,
+            buffer : BytesMut::with_capacity(read_size),
+            read_size,
+            skipped : 0
+        }
+    }
+
+    /// The number of bytes read but not decoded yet, these are part of an incomplete frame
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The number of corrupted bytes discarded since the last frame, these are reported by the next frame
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Read once from the reader, a single read may hold several frames.
+    /// Ready(0) means the stream ended, NotReady means a non blocking reader has nothing for us yet
+    pub fn read_from<R : Read>(&mut self, reader : &mut R) -> Result<Async<usize>> {
+        // Reserving reuses the buffer's memory if the previous frames were dropped, and only allocates otherwise.
+        // The reused memory may be much larger after a large frame, only the part read into is initialized
+        self.buffer.reserve(self.read_size);
+        let start = self.buffer.len();
+        self.buffer.resize(start + self.read_size, 0);
+
+        let result = reader.partial_read_async(&mut self.buffer[start..]);
+        let bytes_read = match result {
+            AsyncResult::Ok(Async::Ready(bytes)) => bytes,
+            _ => 0
+        };
+        self.buffer.truncate(start + bytes_read);
+
+        match result {
+            AsyncResult::Ok(ready) => Ok(ready),
+            AsyncResult::Err(err) => Err(Error::from(err))
+        }
+    }
+
+    /// The next complete frame in the buffer, None if more bytes must be read first
+    pub fn next_frame(&mut self) -> Result<Option<Frame>> {
+        Ok(split_frame_or_resync(self.codec, &self.limit, &mut self.buffer, &mut self.skipped)?.map(|(message_type, payload)| Frame {
+            codec : self.codec,
+            message_type,
+            payload : payload.freeze(),
+            skipped : std::mem::take(&mut self.skipped)
+        }))
+    }
+
+    /// Call once the stream ended, to tell a clean end from one in the middle of a frame
+    pub fn finish(&self) -> Result<()> {
+        if self.buffer.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::PeerClosed)
+        }
+    }
+}
+
+/// Split the next complete frame off the buffer, returns its type and payload.
+/// Makes room in the buffer for the rest of an incomplete frame, once its length is known to be acceptable
+pub(crate) fn split_frame(codec : Codec, limit : &LengthLimit, buffer : &mut BytesMut) -> Result<Option<(u32, BytesMut)>> {
+    let (message_type, length, header_length) = match codec.decode_header(buffer)? {
+        HeaderStatus::Complete { message_type, length, header_length } => (message_type, length as usize, header_length),
+        HeaderStatus::Incomplete(missing) => {
+            buffer.reserve(missing);
+            return Ok(None);
+        }
+    };
+
+    // Checked before waiting for the payload, so an oversized message doesn't make the buffer grow
+    limit.check(message_type, length)?;
+    let frame_length = header_length + length + codec.trailer_length();
+    if buffer.len() < frame_length {
+        buffer.reserve(frame_length - buffer.len());
+        return Ok(None);
+    }
+
+    let (header, rest) = buffer[..frame_length].split_at(header_length);
+    let (data, trailer) = rest.split_at(length);
+    codec.verify_trailer(header, data, trailer)?;
+
+    buffer.advance(header_length);
+    let payload = buffer.split_to(length);
+    buffer.advance(codec.trailer_length());
+    Ok(Some((message_type, payload)))
+}
+
+/// Like split_frame, but with a codec using sync words a corrupted frame is skipped up to the next sync word,
+/// the number of bytes discarded is added to skipped
+pub(crate) fn split_frame_or_resync(codec : Codec, limit : &LengthLimit, buffer : &mut BytesMut, skipped : &mut usize) -> Result<Option<(u32, BytesMut)>> {
+    loop {
+        match split_frame(codec, limit, buffer) {
+            // A corrupted length may look too large as well
+            Err(Error::MalformedHeader(_)) | Err(Error::FrameTooLarge { .. }) | Err(Error::ChecksumMismatch { .. }) if codec.sync() => {
+                *skipped += skip_to_sync_word(buffer);
+            },
+            result => return result
+        }
+    }
+}
+
+// Discard the start of a corrupted frame up to the next sync word, returns the number of bytes discarded.
+// A sync word cut at the end of the buffer is kept, the rest of it may arrive with the next read
+pub(crate) fn skip_to_sync_word(buffer : &mut BytesMut) -> usize {
+    let skipped = (1..buffer.len())
+        .find(|start| {
+            let candidate = &buffer[*start..];
+            let length = candidate.len().min(SYNC_WORD.len());
+            candidate[..length] == SYNC_WORD[..length]
+        })
+        .unwrap_or(buffer.len());
+    buffer.advance(skipped);
+    skipped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn encode(messages : &[Message]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        for message in messages {
+            message.write_to(&mut encoded).unwrap();
+        }
+        encoded
+    }
+
+    #[test]
+    fn frames_are_split_out_of_a_single_read() {
+        let encoded = encode(&[Message::new(1, 5, b"hello".to_vec()), Message::new(2, 5, b"world".to_vec())]);
+        let mut decoder = FrameDecoder::new(Codec::default(), LengthLimit::default());
+
+        assert!(matches!(decoder.read_from(&mut encoded.as_slice()).unwrap(), Async::Ready(bytes) if bytes == encoded.len()));
+        let first = decoder.next_frame().unwrap().unwrap();
+        let second = decoder.next_frame().unwrap().unwrap();
+        assert!(decoder.next_frame().unwrap().is_none());
+        assert_eq!((first.message_type(), &first.payload()[..]), (1, &b"hello"[..]));
+        assert_eq!((second.message_type(), &second.payload()[..]), (2, &b"world"[..]));
+        assert_eq!(second.to_message().unwrap().data(), b"world");
+        let converted = Frame::from(second.to_message().unwrap());
+        assert_eq!((converted.message_type(), converted.payload()), (2, second.payload()));
+        decoder.finish().unwrap();
+    }
+
+    #[test]
+    fn buffer_is_reused_once_frames_are_dropped() {
+        let encoded = encode(&[Message::new(1, 5, b"hello".to_vec())]);
+        let mut decoder = FrameDecoder::new(Codec::default(), LengthLimit::default());
+
+        decoder.read_from(&mut encoded.as_slice()).unwrap();
+        let first = decoder.next_frame().unwrap().unwrap().payload().as_ptr();
+        decoder.read_from(&mut encoded.as_slice()).unwrap();
+        let second = decoder.next_frame().unwrap().unwrap().payload().as_ptr();
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn small_reads_follow_a_large_frame() {
+        let encoded = encode(&[Message::new(1, 1024 * 1024, vec![0; 1024 * 1024])]);
+        let mut decoder = FrameDecoder::new(Codec::default(), LengthLimit::default());
+        let mut reader = encoded.as_slice();
+        while decoder.next_frame().unwrap().is_none() {
+            decoder.read_from(&mut reader).unwrap();
+        }
+
+        // The large frame's memory is reused, but a read only offers the read size
+        let mut reader = Offered(0);
+        decoder.read_from(&mut reader).unwrap();
+        assert_eq!(reader.0, DEFAULT_READ_SIZE);
+    }
+
+    #[test]
+    fn incomplete_frames_wait_for_more_bytes() {
+        let encoded = encode(&[Message::new(1, 5, b"hello".to_vec())]);
+        let mut decoder = FrameDecoder::with_read_size(Codec::default(), LengthLimit::new(5), 4);
+
+        let mut reader = &encoded[..encoded.len() - 1];
+        while let Async::Ready(bytes) = decoder.read_from(&mut reader).unwrap() {
+            if bytes == 0 {
+                break;
+            }
+            assert!(decoder.next_frame().unwrap().is_none());
+        }
+        assert!(matches!(decoder.finish(), Err(Error::PeerClosed)));
+
+        let mut decoder = FrameDecoder::new(Codec::default(), LengthLimit::new(4));
+        decoder.read_from(&mut encoded.as_slice()).unwrap();
+        assert!(matches!(decoder.next_frame(), Err(Error::FrameTooLarge { length : 5, max_length : 4 })));
+
+        assert!(matches!(decoder.read_from(&mut WouldBlock).unwrap(), Async::NotReady));
+    }
+
+    #[test]
+    fn corrupted_bytes_are_skipped_with_sync_words() {
+        let codec = Codec::default().with_sync(true).with_checksum(true);
+        let frames : Vec<Message> = (0..3u8).map(|id| Message::with_codec(codec, 1, 5, vec![id; 5]).unwrap()).collect();
+        let mut encoded = b"noise".to_vec();
+        encoded.extend(encode(&frames[..1]));
+        // Lose the end of the second frame, and corrupt a byte of the third one's payload
+        let second = encode(&frames[1..2]);
+        encoded.extend_from_slice(&second[..second.len() - 3]);
+        let mut third = encode(&frames[2..]);
+        third[9] ^= 0xff;
+        encoded.extend_from_slice(&third);
+        encoded.extend(encode(&frames[..1]));
+
+        let mut decoder = FrameDecoder::new(codec, LengthLimit::default());
+        decoder.read_from(&mut encoded.as_slice()).unwrap();
+        let first = decoder.next_frame().unwrap().unwrap();
+        assert_eq!((first.payload()[0], first.skipped()), (0, 5));
+        let last = decoder.next_frame().unwrap().unwrap();
+        assert_eq!((last.payload()[0], last.skipped()), (0, second.len() - 3 + third.len()));
+        assert!(decoder.next_frame().unwrap().is_none());
+        decoder.finish().unwrap();
+
+        // Without sync words there is no telling where the next frame starts
+        let mut decoder = FrameDecoder::new(Codec::default(), LengthLimit::new(16));
+        decoder.read_from(&mut &b"noise noise"[..]).unwrap();
+        assert!(decoder.next_frame().is_err());
+    }
+
+    // Records the size of the buffer it was offered
+    struct Offered(usize);
+
+    impl io::Read for Offered {
+        fn read(&mut self, buffer : &mut [u8]) -> io::Result<usize> {
+            self.0 = buffer.len();
+            Ok(0)
+        }
+    }
+
+    struct WouldBlock;
+
+    impl io::Read for WouldBlock {
+        fn read(&mut self, _buffer : &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
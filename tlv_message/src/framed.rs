@@ -0,0 +1,205 @@
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::codec::Codec;
+use crate::error::{Error, Result};
+use crate::frame::{skip_to_sync_word, split_frame_or_resync};
+use crate::message::{ByteBuffer, HeaderReader, LengthLimit, Message};
+
+/// Frames messages for tokio, e.g. `Framed::new(stream, MessageCodec::default())`.
+/// With a codec using sync words, corrupted bytes are skipped up to the next message instead of failing the stream
+#[derive(Clone, Debug, Default)]
+pub struct MessageCodec {
+    codec : Codec,
+    limit : LengthLimit,
+    skipped : usize
+}
+
+impl MessageCodec {
+    pub fn new(codec : Codec, limit : LengthLimit) -> MessageCodec {
+        MessageCodec { codec, limit, skipped : 0 }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// The number of corrupted bytes skipped since the codec was created
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src : &mut BytesMut) -> Result<Option<Message>> {
+        match split_frame_or_resync(self.codec, &self.limit, src, &mut self.skipped)? {
+            Some((message_type, payload)) => Message::with_codec(self.codec, message_type, payload.len() as u32, payload.to_vec()).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    fn decode_eof(&mut self, src : &mut BytesMut) -> Result<Option<Message>> {
+        loop {
+            match self.decode(src)? {
+                Some(message) => return Ok(Some(message)),
+                None if src.is_empty() => return Ok(None),
+                // A corrupted length can't be completed anymore, there may be messages after it
+                None if self.codec.sync() => self.skipped += skip_to_sync_word(src),
+                None => return Err(Error::PeerClosed)
+            }
+        }
+    }
+}
+
+impl Encoder<&Message> for MessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, message : &Message, dst : &mut BytesMut) -> Result<()> {
+        // The header was encoded when the message was created, so it must have been created with our codec
+        if message.codec() != self.codec {
+            return Err(Error::MalformedHeader("Message was encoded with a different codec".to_string()));
+        }
+
+        dst.reserve(message.encoded_length());
+        dst.extend_from_slice(message.header());
+        dst.extend_from_slice(message.data());
+        dst.extend_from_slice(message.trailer());
+        Ok(())
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, message : Message, dst : &mut BytesMut) -> Result<()> {
+        self.encode(&message, dst)
+    }
+}
+
+/// Read a message, returns None if the stream ended before the message started
+pub async fn read_message<R : AsyncRead + Unpin>(reader : &mut R, codec : Codec, limit : &LengthLimit) -> Result<Option<Message>> {
+    let mut header = HeaderReader::new(codec);
+    while let Some(buffer) = header.missing_bytes()? {
+        let bytes_read = reader.read(buffer).await?;
+        if !header.advance(bytes_read)? {
+            return Ok(None);
+        }
+    }
+
+    let mut message = header.into_message(limit)?;
+    let (data, trailer) = message.body_mut();
+    // An early end is reported as UnexpectedEof, which converts to PeerClosed
+    reader.read_exact(data).await?;
+    reader.read_exact(trailer).await?;
+    message.verify()?;
+    Ok(Some(message))
+}
+
+/// Write the whole message, the writer isn't flushed
+pub async fn write_message<W : AsyncWrite + Unpin>(writer : &mut W, message : &Message) -> Result<()> {
+    writer.write_all(message.header()).await?;
+    writer.write_all(message.data()).await?;
+    writer.write_all(message.trailer()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_handles_partial_frames() {
+        let first = Message::new(1, 5, b"hello".to_vec());
+        let second = Message::new(2, 0, Vec::new());
+        let mut codec = MessageCodec::default();
+        let mut encoded = BytesMut::new();
+        codec.encode(&first, &mut encoded).unwrap();
+        codec.encode(second, &mut encoded).unwrap();
+
+        // Feed the bytes one at a time, like a slow network would
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in encoded.iter() {
+            src.extend_from_slice(&[*byte]);
+            if let Some(message) = codec.decode(&mut src).unwrap() {
+                decoded.push(message);
+            }
+        }
+
+        assert_eq!(decoded.iter().map(Message::message_type).collect::<Vec<u32>>(), vec![1, 2]);
+        assert_eq!(decoded[0].data(), b"hello");
+        assert!(codec.decode_eof(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn codec_rejects_oversized_and_truncated_frames() {
+        let mut codec = MessageCodec::new(Codec::default(), LengthLimit::new(4));
+        let mut src = BytesMut::new();
+        codec.encode(Message::new(1, 5, b"hello".to_vec()), &mut src).unwrap();
+        assert!(matches!(codec.decode(&mut src), Err(Error::FrameTooLarge { length : 5, max_length : 4 })));
+
+        let mut codec = MessageCodec::default();
+        src.truncate(src.len() - 1);
+        assert!(matches!(codec.decode_eof(&mut src), Err(Error::PeerClosed)));
+    }
+
+    #[tokio::test]
+    async fn codec_skips_corrupted_bytes_with_sync_words() {
+        let codec = Codec::compact().with_sync(true).with_checksum(true);
+        let mut message_codec = MessageCodec::new(codec, LengthLimit::default());
+        let mut encoded = BytesMut::from(&b"line noise"[..]);
+        for id in 0..4u8 {
+            message_codec.encode(Message::with_codec(codec, u32::from(id), 4, vec![id; 4]).unwrap(), &mut encoded).unwrap();
+        }
+        // A sync word, a single byte type and length, the payload and the checksum
+        let frame_length = 2 + 1 + 1 + 4 + 4;
+        // Corrupt the second message's payload, and make the third one claim a longer payload than what is left
+        encoded[10 + frame_length + 4] ^= 0xff;
+        encoded[10 + 2 * frame_length + 3] = 0x7f;
+
+        let (mut writer, mut reader) = tokio::io::duplex(8);
+        let write = async {
+            writer.write_all(&encoded).await.unwrap();
+            drop(writer);
+        };
+        let read = async {
+            let mut src = BytesMut::new();
+            let mut decoded = Vec::new();
+            while reader.read_buf(&mut src).await.unwrap() > 0 {
+                while let Some(message) = message_codec.decode(&mut src).unwrap() {
+                    decoded.push((message.message_type(), message_codec.skipped()));
+                }
+            }
+            while let Some(message) = message_codec.decode_eof(&mut src).unwrap() {
+                decoded.push((message.message_type(), message_codec.skipped()));
+            }
+            decoded
+        };
+
+        let (_, decoded) = tokio::join!(write, read);
+        assert_eq!(decoded, vec![(0, 10), (3, 10 + 2 * frame_length)]);
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip() {
+        let codec = Codec::compact();
+        let (mut client, mut server) = tokio::io::duplex(4);
+        let message = Message::with_codec(codec, 300, 11, b"hello tokio".to_vec()).unwrap();
+
+        let writer = async {
+            write_message(&mut client, &message).await.unwrap();
+            drop(client);
+        };
+        let reader = async {
+            let read = read_message(&mut server, codec, &LengthLimit::default()).await.unwrap().unwrap();
+            assert_eq!(read.message_type(), 300);
+            assert_eq!(read.data(), b"hello tokio");
+            assert!(read_message(&mut server, codec, &LengthLimit::default()).await.unwrap().is_none());
+        };
+        tokio::join!(writer, reader);
+    }
+}
@@ -1,4 +1,16 @@
+pub mod codec;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod error;
+pub mod fields;
+#[cfg(feature = "bytes")]
+pub mod frame;
+#[cfg(feature = "tokio")]
+pub mod framed;
 pub mod message;
+pub mod stream;
+#[cfg(feature = "serde")]
+pub mod typed;
 
 #[cfg(test)]
 mod tests {
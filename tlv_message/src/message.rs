@@ -1,22 +1,26 @@
 use std::io::prelude::*;
 use std::io;
-use std::io::Read;
-use std::borrow::Borrow;
-use std::string::String;
-use byteorder::{NetworkEndian, ByteOrder, ReadBytesExt, WriteBytesExt, NativeEndian};
-use std::net::TcpStream;
-use crate::message::Async::{NotReady, Ready};
-use std::error::Error;
-
-//TODO: We assume we read/write data in network endianness, we should be able to support reading/writing it from/to native endianness as well
-// TODO: If message will implement read trait directly, it will be really easy to compose it with stream (and iterator)
+use std::io::{IoSlice, Read};
+use std::collections::HashMap;
+use std::sync::Arc;
+use byteorder::{BigEndian, ByteOrder};
+use crate::codec::{Codec, HeaderStatus, CHECKSUM_LENGTH, MAX_HEADER_LENGTH};
+use crate::error::{Error, Result};
+
 // TODO: We should be able to return not_ready for write. The client should be able to continue on partial message.
 // TODO: For some weird reason, the reader won't read if there is only a small number of bytes in the stream.
 #[derive(Default, Clone, Debug)]
 pub struct Message {
-    message_type : [u8;2],
-    length : [u8;4],
-    data : Vec<u8>
+    // How the header is laid out on the wire
+    codec : Codec,
+    // The header as it is on the wire, only valid once header_length is set
+    header : [u8; MAX_HEADER_LENGTH],
+    header_length : usize,
+    message_type : u32,
+    length : u32,
+    data : Vec<u8>,
+    // Follows the payload, empty unless the codec uses checksums
+    trailer : [u8; CHECKSUM_LENGTH]
 }
 
 pub enum Async<T> {
@@ -30,8 +34,71 @@ pub enum AsyncResult<T,E> {
 }
 
 pub trait ByteBuffer {
-    fn get_data(&mut self, location : usize) -> &mut [u8];
+    // Writing never modifies the buffer, so an encoded buffer can be shared between many writers
+    fn get_data(&self, location : usize) -> &[u8];
     fn get_storage(&mut self, location : usize) -> &mut [u8];
+
+    /// The data from the given location, possibly in several parts, so a header, its payload and its trailer
+    /// can be written together
+    fn get_data_slices(&self, location : usize) -> [&[u8]; 3] {
+        [self.get_data(location), &[], &[]]
+    }
+
+    /// Called before storage is requested at the given location,
+    /// so the buffer can refuse a malformed header, or to grow past the limit, before anything is allocated
+    fn check_header(&self, _location : usize, _limit : &LengthLimit) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once the buffer was fully read, so the buffer can refuse a corrupted content
+    fn verify(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The default maximal payload length, large enough for any sane message, but small enough that a peer can't
+/// exhaust our memory by announcing a huge message
+pub const DEFAULT_MAX_LENGTH : usize = 16 * 1024 * 1024;
+
+/// Bounds the payload length readers accept, optionally per message type
+#[derive(Clone, Debug)]
+pub struct LengthLimit {
+    max_length : usize,
+    type_max_length : HashMap<u32, usize>
+}
+
+impl LengthLimit {
+    pub fn new(max_length : usize) -> LengthLimit {
+        LengthLimit {
+            max_length,
+            type_max_length : HashMap::new()
+        }
+    }
+
+    /// Use a different limit for a single message type
+    pub fn with_type_limit(mut self, message_type : u32, max_length : usize) -> LengthLimit {
+        self.type_max_length.insert(message_type, max_length);
+        self
+    }
+
+    pub fn max_length(&self, message_type : u32) -> usize {
+        *self.type_max_length.get(&message_type).unwrap_or(&self.max_length)
+    }
+
+    pub fn check(&self, message_type : u32, length : usize) -> Result<()> {
+        let max_length = self.max_length(message_type);
+        if length > max_length {
+            return Err(Error::FrameTooLarge { length, max_length });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for LengthLimit {
+    fn default() -> LengthLimit {
+        LengthLimit::new(DEFAULT_MAX_LENGTH)
+    }
 }
 
 pub trait AsyncRead: std::io::Read {
@@ -46,7 +113,7 @@ pub trait AsyncRead: std::io::Read {
 }
 
 pub trait AsyncWrite: std::io::Write {
-    fn partial_write_async(&mut self, buf: &mut [u8]) -> AsyncResult<Async<usize>, io::Error> {
+    fn partial_write_async(&mut self, buf: &[u8]) -> AsyncResult<Async<usize>, io::Error> {
         match self.write(buf) {
             Ok(bytes) => AsyncResult::Ok(Async::Ready(bytes)),
             Err(ref error) if error.kind() == std::io::ErrorKind::Interrupted => AsyncResult::Ok(Async::NotReady),
@@ -54,26 +121,40 @@ pub trait AsyncWrite: std::io::Write {
             Err(error) => AsyncResult::Err(error)
         }
     }
+
+    fn partial_write_vectored_async(&mut self, bufs: &[IoSlice]) -> AsyncResult<Async<usize>, io::Error> {
+        match self.write_vectored(bufs) {
+            Ok(bytes) => AsyncResult::Ok(Async::Ready(bytes)),
+            Err(ref error) if error.kind() == std::io::ErrorKind::Interrupted => AsyncResult::Ok(Async::NotReady),
+            Err(ref error) if error.kind() == std::io::ErrorKind::WouldBlock => AsyncResult::Ok(Async::NotReady),
+            Err(error) => AsyncResult::Err(error)
+        }
+    }
 }
 
+/// The maximal number of slices handed to a single vectored write, well below the limit of common platforms
+const MAX_BATCH_SLICES : usize = 64;
+
 // AsyncReader/AsyncWriter tries to be as thread safe as possible.
 // The read/write itself are as safe as T and underlying writer/reader allows it to be.
 // In practice all functions but async_write/async_read will be thread safe
 pub struct AsyncReader<T : ByteBuffer + Default> {
     buffer : T,
+    limit : LengthLimit,
     bytes_read : usize,
     done : bool,
     ready : bool,
-    error : Option<io::Error>
+    error : Option<Error>
 }
 
-#[derive(Clone)]
-pub struct AsyncWriter<T : Clone + ByteBuffer + Default> {
-    buffer : T,
+// The buffer is shared, so a message encoded once can be written to many streams,
+// each writer only tracking its own progress
+pub struct AsyncWriter<T : ByteBuffer> {
+    buffer : Arc<T>,
     bytes_written : usize,
     done : bool,
     ready: bool,
-    error : Option<String>
+    error : Option<Error>
 }
 
 pub enum AsyncReadResult<T>
@@ -83,15 +164,25 @@ pub enum AsyncReadResult<T>
 }
 
 pub enum AsyncWriteResult<T>
-    where T : Clone + ByteBuffer + Default {
+    where T : ByteBuffer {
     Ready,
     NotReady(AsyncWriter<T>)
 }
 
 impl<T : ByteBuffer + Default> AsyncReader<T> {
     pub fn new() -> AsyncReader<T> {
+        AsyncReader::with_limit(LengthLimit::default())
+    }
+
+    pub fn with_limit(limit : LengthLimit) -> AsyncReader<T> {
+        AsyncReader::with_buffer(T::default(), limit)
+    }
+
+    /// Read into the given empty buffer, e.g. a message using a non default codec
+    pub fn with_buffer(buffer : T, limit : LengthLimit) -> AsyncReader<T> {
         AsyncReader {
-            buffer : T::default(),
+            buffer,
+            limit,
             bytes_read : 0,
             done : false,
             ready : false,
@@ -103,30 +194,33 @@ impl<T : ByteBuffer + Default> AsyncReader<T> {
         self.done
     }
 
-    // Calling finish on unready object will panic
-    // Should return an enum: T if ready, self if not ready, error if error
-    pub fn finish(mut self) -> io::Result<AsyncReadResult<T>> {
-        if !self.done {
-            panic!("Called finish on message, but reading is not done");
-        }
-
+    /// Returns the message if it was fully read, or the reader itself so the reading can continue later
+    pub fn finish(mut self) -> Result<AsyncReadResult<T>> {
         if let Some(e) = self.error {
             return Err(e)
         }
 
         if self.ready {
-            return Ok(AsyncReadResult::Ready(self.buffer))
+            Ok(AsyncReadResult::Ready(self.buffer))
         } else {
             // Clear the done status for the next run
             self.done = false;
-            return Ok(AsyncReadResult::NotReady(self))
+            Ok(AsyncReadResult::NotReady(self))
         }
-
     }
 
     pub fn async_read<R : AsyncRead>(&mut self, reader : &mut R) {
+        if let Err(error) = self.buffer.check_header(self.bytes_read, &self.limit) {
+            self.error = Some(error);
+            self.done = true;
+            return;
+        }
+
         let buffer = self.buffer.get_storage(self.bytes_read);
         if buffer.is_empty() {
+            if let Err(error) = self.buffer.verify() {
+                self.error = Some(error);
+            }
             self.ready = true;
             self.done = true;
         } else {
@@ -135,12 +229,13 @@ impl<T : ByteBuffer + Default> AsyncReader<T> {
                     self.done = true;
                 },
                 AsyncResult::Ok(Async::Ready(0)) => {
+                    // We still expect more bytes, so the peer must have closed the stream
+                    self.error = Some(Error::PeerClosed);
                     self.done = true;
-                    self.ready = true;
                 },
                 AsyncResult::Ok(Async::Ready(bytes)) => self.bytes_read += bytes,
                 AsyncResult::Err(error) => {
-                    self.error = Some(error);
+                    self.error = Some(Error::from(error));
                     self.done = true;
                 }
             }
@@ -148,8 +243,19 @@ impl<T : ByteBuffer + Default> AsyncReader<T> {
     }
 }
 
-impl<T : Clone + ByteBuffer + Default> AsyncWriter<T> {
+impl<T : ByteBuffer + Default> Default for AsyncReader<T> {
+    fn default() -> AsyncReader<T> {
+        AsyncReader::new()
+    }
+}
+
+impl<T : ByteBuffer> AsyncWriter<T> {
     pub fn new(buffer : T) -> AsyncWriter<T> {
+        AsyncWriter::shared(Arc::new(buffer))
+    }
+
+    /// Write a buffer which may be written by other writers as well
+    pub fn shared(buffer : Arc<T>) -> AsyncWriter<T> {
         AsyncWriter {
             buffer,
             bytes_written : 0,
@@ -163,207 +269,642 @@ impl<T : Clone + ByteBuffer + Default> AsyncWriter<T> {
         self.done
     }
 
-    // Calling finish on unready object will panic
-    pub fn finish(mut self) -> io::Result<AsyncWriteResult<T>> {
-        if !self.done {
-            panic!("Called finish on message, but writing is not done");
-        }
+    pub fn buffer(&self) -> &T {
+        &self.buffer
+    }
+
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
 
+    /// Returns the writer itself if the message wasn't fully written, so the writing can continue later
+    pub fn finish(mut self) -> Result<AsyncWriteResult<T>> {
         if let Some(e) = self.error {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+            return Err(e)
         }
 
         if self.ready {
-            return Ok(AsyncWriteResult::Ready)
+            Ok(AsyncWriteResult::Ready)
         } else {
             // Clear the done status for the next run
             self.done = false;
-            return Ok(AsyncWriteResult::NotReady(self))
+            Ok(AsyncWriteResult::NotReady(self))
         }
     }
 
+    /// Write the header and the payload together, so they can go out in a single syscall
     pub fn async_write<W : AsyncWrite>(&mut self, writer : &mut W) {
-        let mut buffer = self.buffer.get_data(self.bytes_written);
-        match writer.partial_write_async(buffer) {
+        let slices = self.buffer.get_data_slices(self.bytes_written);
+        if slices.iter().all(|slice| slice.is_empty()) {
+            self.ready = true;
+            self.done = true;
+            return;
+        }
+
+        match writer.partial_write_vectored_async(&slices.map(IoSlice::new)) {
             AsyncResult::Ok(Async::NotReady) => {
                 self.done = true;
             },
             AsyncResult::Ok(Async::Ready(0)) => {
+                // The writer can't take any more bytes, and will never be able to
+                self.error = Some(write_zero());
                 self.done = true;
-                self.ready = true;
             },
             AsyncResult::Ok(Async::Ready(bytes)) => self.bytes_written += bytes,
             AsyncResult::Err(error) => {
-                self.error = Some(error.description().to_owned());
+                self.error = Some(Error::from(error));
                 self.done = true;
             }
         }
     }
+
+    /// Write many buffers in order, handing as many of them as possible to each vectored write,
+    /// so a queue of small messages needs only a few syscalls.
+    /// Stops once the writer would block, returns the number of writers which were fully written,
+    /// the next writer may be partially written
+    pub fn write_batch<W : AsyncWrite>(writers : &mut [AsyncWriter<T>], writer : &mut W) -> Result<usize> {
+        let mut completed = 0;
+
+        loop {
+            while completed < writers.len() && writers[completed].remaining() == 0 {
+                writers[completed].ready = true;
+                writers[completed].done = true;
+                completed += 1;
+            }
+            if completed == writers.len() {
+                return Ok(completed);
+            }
+
+            let result = {
+                let mut slices = Vec::with_capacity(MAX_BATCH_SLICES);
+                for pending in &writers[completed..] {
+                    let parts = pending.buffer.get_data_slices(pending.bytes_written);
+                    if slices.len() + parts.len() > MAX_BATCH_SLICES {
+                        break;
+                    }
+                    slices.extend(parts.iter().filter(|slice| !slice.is_empty()).map(|slice| IoSlice::new(slice)));
+                }
+                writer.partial_write_vectored_async(&slices)
+            };
+
+            let mut bytes = match result {
+                AsyncResult::Ok(Async::NotReady) => return Ok(completed),
+                AsyncResult::Ok(Async::Ready(0)) => return Err(write_zero()),
+                AsyncResult::Ok(Async::Ready(bytes)) => bytes,
+                AsyncResult::Err(error) => return Err(Error::from(error))
+            };
+
+            // Spread the written bytes over the writers, in order
+            while bytes > 0 && completed < writers.len() {
+                let pending = &mut writers[completed];
+                let written = bytes.min(pending.remaining());
+                pending.bytes_written += written;
+                bytes -= written;
+                if pending.remaining() == 0 {
+                    pending.ready = true;
+                    pending.done = true;
+                    completed += 1;
+                }
+            }
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.buffer.get_data_slices(self.bytes_written).iter().map(|slice| slice.len()).sum()
+    }
+}
+
+// Cloning a writer is cheap, the clone shares the buffer but has its own progress
+impl<T : ByteBuffer> Clone for AsyncWriter<T> {
+    fn clone(&self) -> AsyncWriter<T> {
+        AsyncWriter {
+            buffer : self.buffer.clone(),
+            bytes_written : self.bytes_written,
+            done : self.done,
+            ready : self.ready,
+            error : self.error.clone()
+        }
+    }
 }
 
-//TODO: Ideally we want to wrap TcpStream and force it to be nonblock
-impl AsyncRead for TcpStream { }
-impl AsyncWrite for TcpStream { }
+// Any reader/writer can be used asynchronously, as long as it was set to be non blocking (e.g. TcpStream::set_nonblocking)
+impl<T : std::io::Read + ?Sized> AsyncRead for T { }
+impl<T : std::io::Write + ?Sized> AsyncWrite for T { }
 
 impl ByteBuffer for Message {
-    fn get_data(&mut self, location : usize) -> &mut [u8] {
-        match location {
-            0...1 => {
-                &mut self.message_type[location..]
-            },
-            2...5 => {
-                &mut self.length[(location - 2)..]
-            },
-            _ => {
-                if location - 6 > self.length() as usize {
-                    &mut []
-                } else {
-                    &mut self.data[(location - 6)..]
-                }
+    fn get_data(&self, location : usize) -> &[u8] {
+        if location < self.header_length {
+            &self.header[location..self.header_length]
+        } else if location - self.header_length < self.data.len() {
+            &self.data[(location - self.header_length)..]
+        } else {
+            let trailer = self.trailer();
+            trailer.get((location - self.header_length - self.data.len())..).unwrap_or(&[])
+        }
+    }
+
+    fn get_data_slices(&self, location : usize) -> [&[u8]; 3] {
+        if location < self.header_length {
+            [&self.header[location..self.header_length], &self.data[..], self.trailer()]
+        } else if location - self.header_length < self.data.len() {
+            [&self.data[(location - self.header_length)..], self.trailer(), &[]]
+        } else {
+            [self.get_data(location), &[], &[]]
+        }
+    }
+
+    fn check_header(&self, location : usize, limit : &LengthLimit) -> Result<()> {
+        // Once the header is read, the length is known but the payload is not allocated yet
+        if self.header_length == 0 {
+            if let HeaderStatus::Complete { message_type, length, .. } = self.codec.decode_header(&self.header[..location])? {
+                limit.check(message_type, length as usize)?;
             }
         }
+
+        Ok(())
     }
 
     fn get_storage(&mut self, location : usize) -> &mut [u8] {
-        match location {
-            0...1 => {
-                &mut self.message_type[location..]
-            },
-            2...5 => {
-                &mut self.length[(location - 2)..]
-            },
-            _ => {
-                if location - 6 > self.length() as usize {
-                    &mut[]
-                } else {
-                    if self.data.len() != self.length() as usize {
-                        // Allocate the buffer if needed
-                        self.data.resize(self.length() as usize, 0);
-                    }
-                    &mut self.data[(location - 6)..]
-                }
+        if self.header_length == 0 {
+            // The header's length depends on its content, so it is read field by field
+            match self.codec.decode_header(&self.header[..location]) {
+                Ok(HeaderStatus::Incomplete(missing)) => return &mut self.header[location..(location + missing)],
+                Ok(HeaderStatus::Complete { message_type, length, header_length }) => {
+                    self.message_type = message_type;
+                    self.length = length;
+                    self.header_length = header_length;
+                    // Allocate the buffer, check_header already made sure the length is acceptable
+                    self.data.resize(length as usize, 0);
+                },
+                // check_header reports malformed headers before storage is requested
+                Err(_) => return &mut []
             }
         }
+
+        let trailer_length = self.codec.trailer_length();
+        if location - self.header_length < self.data.len() {
+            &mut self.data[(location - self.header_length)..]
+        } else {
+            self.trailer[..trailer_length].get_mut((location - self.header_length - self.data.len())..).unwrap_or(&mut [])
+        }
+    }
+
+    fn verify(&self) -> Result<()> {
+        self.codec.verify_trailer(self.header(), &self.data, self.trailer())
     }
 }
 
-//TODO: Create helper function for internal read/write (types/length/data)
 impl Message {
+    /// Create a message using the default codec, which has 2 bytes types
     pub fn new(message_type: u16, length: u32, data: Vec<u8>) -> Message {
-        let mut type_buffer :[u8; 2] = [0;2];
-        NetworkEndian::write_u16(&mut type_buffer[..], message_type);
+        // The default codec's header is a u16 type and a u32 length in network order, so any of them fits
+        let mut header = [0; MAX_HEADER_LENGTH];
+        BigEndian::write_u16(&mut header, message_type);
+        BigEndian::write_u32(&mut header[2..], length);
+
+        Message {
+            codec : Codec::default(),
+            header,
+            header_length : 6,
+            message_type : u32::from(message_type),
+            length,
+            data,
+            trailer : [0; CHECKSUM_LENGTH]
+        }
+    }
+
+    /// Create a message using the given codec, fails if the type or length don't fit in the codec's header,
+    /// or if the length isn't the payload's length
+    pub fn with_codec(codec : Codec, message_type: u32, length: u32, data: Vec<u8>) -> Result<Message> {
+        // The header is all a reader has to find the next message, so it can't disagree with the payload
+        if length as usize != data.len() {
+            return Err(Error::MalformedHeader(format!("Length {} doesn't match the payload's {} bytes", length, data.len())));
+        }
 
-        let mut length_buffer :[u8; 4] = [0;4];
-        NetworkEndian::write_u32(&mut length_buffer[..], length);
+        let mut header = [0; MAX_HEADER_LENGTH];
+        let header_length = codec.encode_header(message_type, length, &mut header)?;
+        let mut trailer = [0; CHECKSUM_LENGTH];
+        codec.encode_trailer(&header[..header_length], &data, &mut trailer);
+
+        Ok(Message {
+            codec,
+            header,
+            header_length,
+            message_type,
+            length,
+            data,
+            trailer
+        })
+    }
 
+    /// An empty message to read into, using the given codec
+    pub fn empty(codec : Codec) -> Message {
         Message {
-            message_type : type_buffer,
-            length : length_buffer,
-            data
+            codec,
+            ..Message::default()
         }
     }
 
-    pub fn message_type(&self) -> u16 {
-        NetworkEndian::read_u16(&self.message_type[..])
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn message_type(&self) -> u32 {
+        self.message_type
     }
 
     pub fn length(&self) -> u32 {
-        NetworkEndian::read_u32(&self.length[..])
+        self.length
     }
 
     pub fn data(&self) -> &[u8] {
         &self.data[..]
     }
 
-    pub fn from_reader<T: Read>(reader: &mut T) -> io::Result<Self> {
-        let mut message = Message {
-            message_type: [0; 2],
-            length: [0; 4],
-            data: Vec::new()
-        };
+    /// Take the payload out of the message, without copying it
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
 
-        // Read Type
-        let mut total_bytes_read = 0;
-        while total_bytes_read < 2 {
-            total_bytes_read += reader.read(&mut message.message_type[total_bytes_read..]).or_else(|err| {
-                if err.kind() == std::io::ErrorKind::Interrupted {
-                    Ok(0)
-                } else {
-                    Err(err)
-                }
-            })?;
-        };
+    /// Read the payload as a stream, e.g. to decode its fields with the byteorder extensions
+    pub fn payload_reader(&self) -> io::Cursor<&[u8]> {
+        io::Cursor::new(&self.data[..])
+    }
 
-        // Read Length
-        total_bytes_read = 0;
-        while total_bytes_read < 4 {
-            total_bytes_read += reader.read(&mut message.length[total_bytes_read..]).or_else(|err| {
-                if err.kind() == std::io::ErrorKind::Interrupted {
-                    Ok(0)
-                } else {
-                    Err(err)
-                }
-            })?;
-        };
+    /// The size of the message on the wire, including the header and the trailer
+    pub fn encoded_length(&self) -> usize {
+        self.header_length + self.data.len() + self.codec.trailer_length()
+    }
 
-        // Read Data
-        total_bytes_read = 0;
-        message.data = vec![0; message.length() as usize];
-        while total_bytes_read < message.length() as usize {
-            total_bytes_read += reader.read(&mut message.data[total_bytes_read..]).or_else(|err| {
-                if err.kind() == std::io::ErrorKind::Interrupted {
-                    Ok(0)
-                } else {
-                    Err(err)
-                }
-            })?;
-        };
+    /// The encoded header, as written ahead of the payload
+    pub(crate) fn header(&self) -> &[u8] {
+        &self.header[..self.header_length]
+    }
 
-        Ok(message)
+    /// The encoded trailer, as written after the payload
+    pub(crate) fn trailer(&self) -> &[u8] {
+        &self.trailer[..self.codec.trailer_length()]
     }
 
-    pub fn into_writer<T: Write>(mut self, writer: &mut T) -> io::Result<()> {
+    /// Read a message, limiting its payload to the default maximal length
+    pub fn from_reader<T: Read>(reader: &mut T) -> Result<Self> {
+        Message::from_reader_with_limit(reader, &LengthLimit::default())
+    }
 
-        let mut total_bytes_written = 0;
+    /// Read a message, an oversized message is rejected before its payload is read
+    pub fn from_reader_with_limit<T: Read>(reader: &mut T, limit : &LengthLimit) -> Result<Self> {
+        Message::from_reader_with_codec(reader, Codec::default(), limit)
+    }
 
-        //TODO: Handle a case of 0 bytes written, it usually mean we have some kind of failure (buffer run out), and we might never manage to get out of the loop.
-        // This is true for all writes loop below
+    pub fn from_reader_with_codec<T: Read>(reader: &mut T, codec : Codec, limit : &LengthLimit) -> Result<Self> {
+        Message::from_reader_or_end(reader, codec, limit)?.ok_or(Error::PeerClosed)
+    }
 
-        // Write Type
-        while total_bytes_written < 2 as usize {
-            total_bytes_written += writer.write(&mut self.message_type[total_bytes_written..]).or_else(|err| {
-                if err.kind() == std::io::ErrorKind::Interrupted {
-                    Ok(0)
-                } else {
-                    Err(err)
-                }
-            })?;
-        };
+    /// Read a message, returns None if the stream ended before the message started
+    pub(crate) fn from_reader_or_end<T: Read>(reader: &mut T, codec : Codec, limit : &LengthLimit) -> Result<Option<Self>> {
+        let mut header = HeaderReader::new(codec);
+        while let Some(buffer) = header.missing_bytes()? {
+            let bytes_read = read_available(reader, buffer)?;
+            if !header.advance(bytes_read)? {
+                return Ok(None);
+            }
+        }
 
-        // Write Length
-        total_bytes_written = 0;
-        while total_bytes_written < 4 as usize {
-            total_bytes_written += writer.write(&mut self.length[total_bytes_written..]).or_else(|err| {
-                if err.kind() == std::io::ErrorKind::Interrupted {
-                    Ok(0)
-                } else {
-                    Err(err)
-                }
-            })?;
-        };
+        let mut message = header.into_message(limit)?;
+        let (data, trailer) = message.body_mut();
+        read_exactly(reader, data)?;
+        read_exactly(reader, trailer)?;
+        message.verify()?;
+        Ok(Some(message))
+    }
 
-        // Write Data
-        total_bytes_written = 0;
-        while total_bytes_written < self.length() as usize {
-            total_bytes_written += writer.write(&mut self.data[total_bytes_written..]).or_else(|err| {
-                if err.kind() == std::io::ErrorKind::Interrupted {
-                    Ok(0)
-                } else {
-                    Err(err)
-                }
-            })?;
+    // The payload and trailer of a message being read
+    pub(crate) fn body_mut(&mut self) -> (&mut [u8], &mut [u8]) {
+        let trailer_length = self.codec.trailer_length();
+        (&mut self.data, &mut self.trailer[..trailer_length])
+    }
+
+    pub fn into_writer<T: Write>(self, writer: &mut T) -> Result<()> {
+        self.write_to(writer)
+    }
+
+    /// Write the message, keeping it for later use
+    pub fn write_to<T: Write>(&self, writer: &mut T) -> Result<()> {
+        write_exactly(writer, self.header())?;
+        write_exactly(writer, &self.data)?;
+        write_exactly(writer, self.trailer())
+    }
+}
+
+/// The steps of reading a message which don't depend on how the bytes are read,
+/// shared by the blocking and the asynchronous readers
+pub(crate) struct HeaderReader {
+    codec : Codec,
+    header : [u8; MAX_HEADER_LENGTH],
+    bytes_read : usize
+}
+
+impl HeaderReader {
+    pub(crate) fn new(codec : Codec) -> HeaderReader {
+        HeaderReader { codec, header : [0; MAX_HEADER_LENGTH], bytes_read : 0 }
+    }
+
+    /// Where the next bytes of the header go, None once the header is complete
+    pub(crate) fn missing_bytes(&mut self) -> Result<Option<&mut [u8]>> {
+        // The header's length depends on its content, so it is read field by field
+        match self.codec.decode_header(&self.header[..self.bytes_read])? {
+            HeaderStatus::Complete { .. } => Ok(None),
+            HeaderStatus::Incomplete(missing) => Ok(Some(&mut self.header[self.bytes_read..(self.bytes_read + missing)]))
+        }
+    }
+
+    /// Account for the bytes read into the missing part, returns false if the stream ended before the message started
+    pub(crate) fn advance(&mut self, bytes_read : usize) -> Result<bool> {
+        match bytes_read {
+            // Ending between messages is fine
+            0 if self.bytes_read == 0 => Ok(false),
+            0 => Err(Error::PeerClosed),
+            bytes => {
+                self.bytes_read += bytes;
+                Ok(true)
+            }
+        }
+    }
+
+    /// The message the complete header starts, with room for its payload and trailer.
+    /// An oversized message is rejected before anything is allocated for it
+    pub(crate) fn into_message(self, limit : &LengthLimit) -> Result<Message> {
+        let (message_type, length, header_length) = match self.codec.decode_header(&self.header[..self.bytes_read])? {
+            HeaderStatus::Complete { message_type, length, header_length } => (message_type, length, header_length),
+            HeaderStatus::Incomplete(_) => return Err(Error::MalformedHeader("Message header is incomplete".to_string()))
         };
 
-        Ok(())
+        limit.check(message_type, length as usize)?;
+        Ok(Message {
+            codec : self.codec,
+            header : self.header,
+            header_length,
+            message_type,
+            length,
+            data : vec![0; length as usize],
+            trailer : [0; CHECKSUM_LENGTH]
+        })
+    }
+}
+
+// Unlike Read::read_exact, a stream closed in the middle is reported as such, and not as an I/O error
+fn read_exactly<T: Read>(reader: &mut T, buffer: &mut [u8]) -> Result<()> {
+    if read_available(reader, buffer)? < buffer.len() {
+        return Err(Error::PeerClosed);
+    }
+
+    Ok(())
+}
+
+// Fill the buffer unless the stream ends first, returns the number of bytes read
+fn read_available<T: Read>(reader: &mut T, buffer: &mut [u8]) -> Result<usize> {
+    let mut total_bytes_read = 0;
+    while total_bytes_read < buffer.len() {
+        match reader.read(&mut buffer[total_bytes_read..]) {
+            Ok(0) => break,
+            Ok(bytes) => total_bytes_read += bytes,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(Error::Io(err))
+        }
+    }
+
+    Ok(total_bytes_read)
+}
+
+fn write_exactly<T: Write>(writer: &mut T, buffer: &[u8]) -> Result<()> {
+    let mut total_bytes_written = 0;
+    while total_bytes_written < buffer.len() {
+        match writer.write(&buffer[total_bytes_written..]) {
+            // The writer can't take any more bytes, retrying would loop forever
+            Ok(0) => return Err(write_zero()),
+            Ok(bytes) => total_bytes_written += bytes,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(Error::Io(err))
+        }
+    }
+
+    Ok(())
+}
+
+fn write_zero() -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::WriteZero, "failed to write the whole message"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Endianness;
+
+    #[test]
+    fn writers_share_the_encoded_message() {
+        let message = Arc::new(Message::new(7, 5, b"hello".to_vec()));
+        let mut first = AsyncWriter::shared(message.clone());
+        let mut second = first.clone();
+
+        let mut first_output = Vec::new();
+        while !first.done() {
+            first.async_write(&mut first_output);
+        }
+
+        // The second writer has its own progress, so it still writes the whole message
+        let mut second_output = Vec::new();
+        while !second.done() {
+            second.async_write(&mut second_output);
+        }
+
+        assert_eq!(first_output, b"\x00\x07\x00\x00\x00\x05hello");
+        assert_eq!(first_output, second_output);
+        assert_eq!(Arc::strong_count(&message), 3);
+    }
+
+    #[test]
+    fn closed_stream_is_reported() {
+        let mut input : &[u8] = b"\x00\x07\x00\x00\x00\x05hel";
+        match Message::from_reader(&mut input) {
+            Err(Error::PeerClosed) => {},
+            _ => panic!("Expected the stream to be closed")
+        }
+    }
+
+    #[test]
+    fn oversized_message_is_rejected_before_reading_it() {
+        let limit = LengthLimit::new(4).with_type_limit(8, 16);
+        let header : &[u8] = b"\x00\x07\xff\xff\xff\xff";
+        match Message::from_reader_with_limit(&mut &header[..], &limit) {
+            Err(Error::FrameTooLarge { length, max_length }) => assert_eq!((length, max_length), (0xffff_ffff, 4)),
+            _ => panic!("Expected the message to be too large")
+        }
+
+        let mut reader = AsyncReader::<Message>::with_limit(limit.clone());
+        let mut input = header;
+        while !reader.done() {
+            reader.async_read(&mut input);
+        }
+        assert!(matches!(reader.finish(), Err(Error::FrameTooLarge { .. })));
+
+        // The type's own limit overrides the default one
+        let mut input : &[u8] = b"\x00\x08\x00\x00\x00\x05hello";
+        assert!(Message::from_reader_with_limit(&mut input, &limit).is_ok());
+    }
+
+    // A non blocking stream, which received a single byte so far
+    struct SingleByte(Option<u8>);
+
+    impl Read for SingleByte {
+        fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+            match self.0.take() {
+                Some(byte) => {
+                    buf[0] = byte;
+                    Ok(1)
+                },
+                None => Err(io::Error::new(io::ErrorKind::WouldBlock, "no more bytes yet"))
+            }
+        }
+    }
+
+    fn read_byte_by_byte(codec : Codec, bytes : &[u8]) -> Message {
+        let mut reader = AsyncReader::with_buffer(Message::empty(codec), LengthLimit::default());
+        for byte in bytes.iter() {
+            let mut input = SingleByte(Some(*byte));
+            while !reader.done() {
+                reader.async_read(&mut input);
+            }
+            reader = match reader.finish() {
+                Ok(AsyncReadResult::NotReady(reader)) => reader,
+                Ok(AsyncReadResult::Ready(message)) => return message,
+                Err(err) => panic!("Failed to read the message {}", err)
+            };
+        }
+        panic!("Expected a message");
+    }
+
+    #[test]
+    fn round_trip_with_each_codec() {
+        let big = Codec::default().with_endianness(Endianness::Big);
+        let little = Codec::default().with_endianness(Endianness::Little);
+        let expected : [(Codec, &[u8]); 3] = [
+            (big, b"\x01\x02\x00\x00\x00\x02hi"),
+            (little, b"\x02\x01\x02\x00\x00\x00hi"),
+            (Codec::compact(), b"\x82\x02\x02hi")
+        ];
+
+        for (codec, encoded) in expected.iter() {
+            let mut output = Vec::new();
+            Message::with_codec(*codec, 0x0102, 2, b"hi".to_vec()).unwrap().into_writer(&mut output).unwrap();
+            assert_eq!(&output[..], *encoded);
+
+            let message = Message::from_reader_with_codec(&mut &output[..], *codec, &LengthLimit::default()).unwrap();
+            assert_eq!((message.message_type(), message.data()), (0x0102, &b"hi"[..]));
+
+            // The asynchronous reader reads the same message, even when it arrives byte by byte
+            let message = read_byte_by_byte(*codec, &output);
+            assert_eq!((message.message_type(), message.data()), (0x0102, &b"hi"[..]));
+        }
+
+        assert!(matches!(Message::with_codec(Codec::default(), 1, 3, b"hi".to_vec()), Err(Error::MalformedHeader(_))));
+
+        // Message::new builds the default codec's header on its own
+        let mut output = Vec::new();
+        Message::new(0x0102, 2, b"hi".to_vec()).write_to(&mut output).unwrap();
+        assert_eq!(&output[..], expected[0].1);
+    }
+
+    #[test]
+    fn corrupted_message_is_detected() {
+        let codec = Codec::default().with_checksum(true);
+        let mut output = Vec::new();
+        Message::with_codec(codec, 7, 5, b"hello".to_vec()).unwrap().write_to(&mut output).unwrap();
+        assert_eq!(output.len(), 6 + 5 + 4);
+
+        let message = read_byte_by_byte(codec, &output);
+        assert_eq!(message.data(), b"hello");
+
+        // Flip a bit in the payload, then in the length, which shifts the trailer
+        for position in &[8, 5] {
+            let mut corrupted = output.clone();
+            corrupted[*position] ^= 1;
+            corrupted.extend_from_slice(&[0; 4]);
+            assert!(matches!(Message::from_reader_with_codec(&mut &corrupted[..], codec, &LengthLimit::default()),
+                             Err(Error::ChecksumMismatch { .. })));
+
+            let mut reader = AsyncReader::with_buffer(Message::empty(codec), LengthLimit::default());
+            let mut input = &corrupted[..];
+            while !reader.done() {
+                reader.async_read(&mut input);
+            }
+            assert!(matches!(reader.finish(), Err(Error::ChecksumMismatch { .. })));
+        }
+    }
+
+    #[test]
+    fn full_writer_is_an_error() {
+        let mut writer = AsyncWriter::new(Message::new(7, 5, b"hello".to_vec()));
+        let mut buffer = [0u8; 4];
+        let mut output = &mut buffer[..];
+        while !writer.done() {
+            writer.async_write(&mut output);
+        }
+
+        match writer.finish() {
+            Err(Error::Io(error)) => assert_eq!(error.kind(), io::ErrorKind::WriteZero),
+            _ => panic!("Expected a write error")
+        }
+    }
+
+    // A non blocking stream supporting vectored writes, which counts the syscalls it would have made
+    struct VectoredWriter {
+        output : Vec<u8>,
+        budget : usize,
+        calls : usize
+    }
+
+    impl Write for VectoredWriter {
+        fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs : &[IoSlice]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "no room left"));
+            }
+
+            self.calls += 1;
+            let start = self.output.len();
+            for buf in bufs {
+                let bytes = buf.len().min(self.budget - (self.output.len() - start));
+                self.output.extend_from_slice(&buf[..bytes]);
+            }
+            self.budget -= self.output.len() - start;
+            Ok(self.output.len() - start)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn batched_writers_share_syscalls() {
+        let mut writers : Vec<AsyncWriter<Message>> = (0..3).map(|id| AsyncWriter::new(Message::new(id, 2, vec![id as u8; 2]))).collect();
+        let mut writer = VectoredWriter { output : Vec::new(), budget : 12, calls : 0 };
+
+        // Every message is 8 bytes long, so the second one is cut in the middle
+        assert_eq!(AsyncWriter::write_batch(&mut writers, &mut writer).unwrap(), 1);
+        assert_eq!((writers[1].bytes_written(), writer.calls), (4, 1));
+
+        writer.budget = usize::MAX;
+        assert_eq!(AsyncWriter::write_batch(&mut writers[1..], &mut writer).unwrap(), 2);
+        assert_eq!(writer.calls, 2);
+
+        let mut reader = &writer.output[..];
+        for id in 0..3 {
+            assert_eq!(Message::from_reader(&mut reader).unwrap().data(), &[id as u8; 2]);
+        }
+        assert!(matches!(writers.pop().unwrap().finish(), Ok(AsyncWriteResult::Ready)));
     }
 }
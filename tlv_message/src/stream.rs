@@ -0,0 +1,126 @@
+use std::io::{BufWriter, Read, Write};
+
+use crate::codec::Codec;
+use crate::error::{Error, Result};
+use crate::message::{LengthLimit, Message};
+
+/// Reads the messages of a stream one after the other, until the stream ends.
+/// The stream may end between messages, ending in the middle of one is an error
+pub struct MessageReader<R : Read> {
+    reader : R,
+    codec : Codec,
+    limit : LengthLimit,
+    // Once the stream ended or failed, there is no way to find the next message
+    finished : bool
+}
+
+impl<R : Read> MessageReader<R> {
+    pub fn new(reader : R) -> MessageReader<R> {
+        MessageReader::with_limit(reader, LengthLimit::default())
+    }
+
+    pub fn with_limit(reader : R, limit : LengthLimit) -> MessageReader<R> {
+        MessageReader::with_codec(reader, Codec::default(), limit)
+    }
+
+    pub fn with_codec(reader : R, codec : Codec, limit : LengthLimit) -> MessageReader<R> {
+        MessageReader {
+            reader,
+            codec,
+            limit,
+            finished : false
+        }
+    }
+
+    /// Read the next message, failing if the stream ended
+    pub fn read_message(&mut self) -> Result<Message> {
+        self.next().unwrap_or(Err(Error::PeerClosed))
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R : Read> Iterator for MessageReader<R> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Result<Message>> {
+        if self.finished {
+            return None;
+        }
+
+        let result = Message::from_reader_or_end(&mut self.reader, self.codec, &self.limit).transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.finished = true;
+        }
+        result
+    }
+}
+
+/// Buffers the messages written to a stream, so each of them doesn't cost a few system calls.
+/// Each message is written with its own codec.
+/// Messages are only guaranteed to reach the stream once flushed
+pub struct MessageWriter<W : Write> {
+    writer : BufWriter<W>
+}
+
+impl<W : Write> MessageWriter<W> {
+    pub fn new(writer : W) -> MessageWriter<W> {
+        MessageWriter {
+            writer : BufWriter::new(writer)
+        }
+    }
+
+    pub fn write_message(&mut self, message : &Message) -> Result<()> {
+        message.write_to(&mut self.writer)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush().map_err(Error::Io)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+
+    /// Flush the pending messages, and return the underlying stream
+    pub fn into_inner(self) -> Result<W> {
+        self.writer.into_inner().map_err(|err| Error::Io(err.into_error()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{NetworkEndian, ReadBytesExt};
+
+    #[test]
+    fn messages_round_trip() {
+        let mut writer = MessageWriter::new(Vec::new());
+        for id in 0..3u8 {
+            writer.write_message(&Message::new(id as u16, 2, vec![0, id])).unwrap();
+        }
+        let output = writer.into_inner().unwrap();
+
+        let messages = MessageReader::new(&output[..]).collect::<Result<Vec<Message>>>().unwrap();
+        assert_eq!(messages.len(), 3);
+        for (id, message) in messages.iter().enumerate() {
+            assert_eq!(message.message_type(), id as u32);
+            assert_eq!(message.payload_reader().read_u16::<NetworkEndian>().unwrap(), id as u16);
+        }
+    }
+
+    #[test]
+    fn stream_ending_mid_message_is_an_error() {
+        let input : &[u8] = b"\x00\x01\x00\x00\x00\x01a\x00\x01\x00";
+        let mut reader = MessageReader::new(input);
+        assert!(reader.next().unwrap().is_ok());
+        assert!(matches!(reader.next(), Some(Err(Error::PeerClosed))));
+        assert!(reader.next().is_none());
+    }
+}
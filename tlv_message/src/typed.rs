@@ -0,0 +1,112 @@
+use std::convert::TryFrom;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::codec::{Codec, Endianness};
+use crate::error::{Error, Result};
+use crate::message::Message;
+
+/// A type carried as a message payload, serialized in a compact binary format.
+/// Each payload type registers the message type identifying it on the wire
+pub trait Payload : Serialize + DeserializeOwned {
+    const MESSAGE_TYPE : u32;
+}
+
+impl Message {
+    /// A message holding the serialized payload, using the default codec
+    pub fn from_payload<T : Payload>(payload : &T) -> Result<Message> {
+        Message::from_payload_with_codec(Codec::default(), payload)
+    }
+
+    /// The payload follows the codec's byte order, like the rest of the message
+    pub fn from_payload_with_codec<T : Payload>(codec : Codec, payload : &T) -> Result<Message> {
+        let data = serialize(codec, payload)
+            .map_err(|err| Error::ProtocolViolation(format!("Failed to serialize payload of type {}: {}", T::MESSAGE_TYPE, err)))?;
+        let length = u32::try_from(data.len()).map_err(|_| Error::FrameTooLarge { length : data.len(), max_length : u32::MAX as usize })?;
+        Message::with_codec(codec, T::MESSAGE_TYPE, length, data)
+    }
+
+    /// Whether the message carries a payload of the given type
+    pub fn holds<T : Payload>(&self) -> bool {
+        self.message_type() == T::MESSAGE_TYPE
+    }
+
+    /// Deserialize the payload, the message must be of the type registered for it
+    pub fn payload<T : Payload>(&self) -> Result<T> {
+        if !self.holds::<T>() {
+            return Err(Error::UnknownType(self.message_type()));
+        }
+
+        deserialize(self.codec(), self.data())
+            .map_err(|err| Error::ProtocolViolation(format!("Invalid payload of type {}: {}", T::MESSAGE_TYPE, err)))
+    }
+}
+
+fn serialize<T : Serialize>(codec : Codec, payload : &T) -> bincode::Result<Vec<u8>> {
+    let options = bincode::DefaultOptions::new();
+    match codec.endianness() {
+        Endianness::Big => options.with_big_endian().serialize(payload),
+        Endianness::Little => options.with_little_endian().serialize(payload)
+    }
+}
+
+// The payload can't hold more than its own length, so lengths claimed inside it are bounded by it,
+// rather than letting a malicious peer make us allocate whatever it claims
+fn deserialize<T : DeserializeOwned>(codec : Codec, data : &[u8]) -> bincode::Result<T> {
+    let options = bincode::DefaultOptions::new().with_limit(data.len() as u64);
+    match codec.endianness() {
+        Endianness::Big => options.with_big_endian().deserialize(data),
+        Endianness::Little => options.with_little_endian().deserialize(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct KickUser {
+        room : String,
+        nickname : String,
+        ban_seconds : Option<u64>
+    }
+
+    impl Payload for KickUser {
+        const MESSAGE_TYPE : u32 = 200;
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Ping(u64);
+
+    impl Payload for Ping {
+        const MESSAGE_TYPE : u32 = 201;
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        let kick = KickUser { room : "rust".to_string(), nickname : "spammer".to_string(), ban_seconds : Some(3600) };
+
+        for codec in &[Codec::default(), Codec::default().with_endianness(Endianness::Little), Codec::compact()] {
+            let message = Message::from_payload_with_codec(*codec, &kick).unwrap();
+            assert!(message.holds::<KickUser>());
+
+            let mut buffer = Vec::new();
+            message.write_to(&mut buffer).unwrap();
+            let read = Message::from_reader_with_codec(&mut buffer.as_slice(), *codec, &Default::default()).unwrap();
+            assert_eq!(read.payload::<KickUser>().unwrap(), kick);
+        }
+    }
+
+    #[test]
+    fn mismatched_payloads_are_rejected() {
+        let message = Message::from_payload(&Ping(7)).unwrap();
+        assert!(matches!(message.payload::<KickUser>(), Err(Error::UnknownType(201))));
+
+        // A length claiming more than the payload holds must not be trusted
+        let bogus = Message::with_codec(Codec::default(), KickUser::MESSAGE_TYPE, 5, vec![0xfc, 0xff, 0xff, 0xff, 0x7f]).unwrap();
+        assert!(matches!(bogus.payload::<KickUser>(), Err(Error::ProtocolViolation(_))));
+    }
+}